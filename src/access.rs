@@ -0,0 +1,125 @@
+use super::config_manager::{AccessConfig, Permission};
+
+/// The outcome of checking a command against the access config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    Granted,
+    /// Blocked users and chats get no answer at all
+    Ignored,
+    /// Refused, with a reason for the chat
+    Denied(String),
+}
+
+/// The level of a user in a chat, `None` when the user or the chat is blocked
+#[must_use]
+pub fn permission_of(
+    access: &AccessConfig,
+    user_id: Option<u64>,
+    chat_id: i64,
+) -> Option<Permission> {
+    let user_as_chat = user_id.and_then(|id| i64::try_from(id).ok());
+    if access.blocked_ids.contains(&chat_id)
+        || user_as_chat.is_some_and(|id| access.blocked_ids.contains(&id))
+    {
+        return None;
+    }
+
+    if user_id.is_some_and(|id| access.admin_ids.contains(&id)) {
+        return Some(Permission::Admin);
+    }
+
+    let open_to_all = access.allowed_user_ids.is_empty() && access.allowed_chat_ids.is_empty();
+    if open_to_all
+        || access.allowed_chat_ids.contains(&chat_id)
+        || user_id.is_some_and(|id| access.allowed_user_ids.contains(&id))
+    {
+        Some(Permission::Member)
+    } else {
+        Some(Permission::Public)
+    }
+}
+
+/// The level needed for `command`
+#[must_use]
+pub fn required_level(access: &AccessConfig, command: &str) -> Permission {
+    access
+        .command_levels
+        .get(command)
+        .copied()
+        .unwrap_or(access.default_level)
+}
+
+/// Check whether a user may run `command` in a chat
+#[must_use]
+pub fn check_access(
+    access: &AccessConfig,
+    command: &str,
+    user_id: Option<u64>,
+    chat_id: i64,
+) -> Access {
+    let Some(level) = permission_of(access, user_id, chat_id) else {
+        return Access::Ignored;
+    };
+
+    match required_level(access, command) {
+        required if level >= required => Access::Granted,
+        Permission::Admin => Access::Denied(format!("Only admins can use /{command}.")),
+        _ => Access::Denied(format!(
+            "You are not allowed to use /{command}, ask an admin to add you or this chat."
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AccessConfig {
+        AccessConfig {
+            admin_ids: vec![1],
+            allowed_user_ids: vec![2],
+            allowed_chat_ids: vec![-100],
+            blocked_ids: vec![3, -200],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_permission_of() {
+        let access = config();
+        assert_eq!(permission_of(&access, Some(1), 5), Some(Permission::Admin));
+        assert_eq!(permission_of(&access, Some(2), 5), Some(Permission::Member));
+        assert_eq!(
+            permission_of(&access, Some(4), -100),
+            Some(Permission::Member)
+        );
+        assert_eq!(permission_of(&access, Some(4), 4), Some(Permission::Public));
+        assert_eq!(permission_of(&access, Some(3), -100), None);
+        assert_eq!(permission_of(&access, Some(1), -200), None);
+
+        // Without allowlists everyone who is not blocked is a member
+        let open = AccessConfig::default();
+        assert_eq!(permission_of(&open, Some(4), 4), Some(Permission::Member));
+    }
+
+    #[test]
+    fn test_check_access() {
+        let mut access = config();
+        access
+            .command_levels
+            .insert("image".to_string(), Permission::Admin);
+
+        assert_eq!(check_access(&access, "help", Some(4), 4), Access::Granted);
+        assert!(matches!(
+            check_access(&access, "chat", Some(4), 4),
+            Access::Denied(_)
+        ));
+        assert_eq!(check_access(&access, "chat", Some(2), 4), Access::Granted);
+        assert!(matches!(
+            check_access(&access, "image", Some(2), 4),
+            Access::Denied(_)
+        ));
+        assert_eq!(check_access(&access, "image", Some(1), 4), Access::Granted);
+        assert_eq!(check_access(&access, "help", Some(3), 4), Access::Ignored);
+    }
+}
@@ -0,0 +1,279 @@
+use super::api_error::{check_status, ApiError};
+use super::chat_history::MessageChat;
+use super::config_manager::{ConfigManager, RetryConfig};
+use super::provider::{
+    split_system, without_tool_turns, ChatCompletion, ChatProvider, ChatRequest, LineDecoder,
+};
+use super::retry::send_with_retry;
+use super::usage::TokenUsage;
+use super::vision::encode_image;
+
+use log::trace;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::env;
+use tokio::sync::watch;
+
+use anyhow::{anyhow, Context, Result};
+
+use async_trait::async_trait;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Client for an Anthropic messages style API
+pub struct AnthropicApi {
+    uri: String,
+    version: String,
+    token: String,
+    retry: RetryConfig,
+}
+
+impl AnthropicApi {
+    /// Form an Anthropic interface, does not make any requests by itself
+    /// # Errors
+    /// Config file errors or `ANTHROPIC_TOKEN` not defined
+    pub fn new() -> Result<Self> {
+        if env::var("ANTHROPIC_TOKEN").is_err() {
+            dotenv::dotenv().ok();
+        }
+        let token = env::var("ANTHROPIC_TOKEN").context("Anthropic token not defined!")?;
+
+        let config = ConfigManager::new()?;
+        Ok(Self {
+            uri: config.providers.anthropic_uri,
+            version: config.providers.anthropic_version,
+            token,
+            retry: config.retry,
+        })
+    }
+
+    async fn anthropic_post(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response> {
+        // System prompts are a separate field and the turns have to alternate
+        let (system, messages) = split_system(&without_tool_turns(&request.messages));
+        let request_data = RequestAnthropic {
+            model: request.model.clone(),
+            max_tokens: request.max_tokens,
+            system,
+            messages: messages.iter().map(MessageAnthropic::from).collect(),
+            stream,
+        };
+
+        let body = serde_json::to_string(&request_data)?;
+        trace!("Anthropic request body: {body}");
+
+        let client = reqwest::Client::new();
+        let request = client
+            .post(format!("{}/messages", self.uri))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("x-api-key", &self.token)
+            .header("anthropic-version", &self.version)
+            .body(body);
+        let response = send_with_retry(request, &self.retry).await?;
+
+        check_status(response).await
+    }
+}
+
+#[async_trait]
+impl ChatProvider for AnthropicApi {
+    async fn complete(&self, request: &ChatRequest) -> Result<ChatCompletion> {
+        let response = self.anthropic_post(request, false).await?.text().await?;
+        trace!("Anthropic response: {response}");
+        let json: ResponseAnthropic = serde_json::from_str(&response)?;
+
+        let output: String = json
+            .content
+            .iter()
+            .filter_map(|block| block.text.as_deref())
+            .collect();
+
+        if output.is_empty() {
+            return Err(anyhow!("No output found."));
+        }
+
+        Ok(ChatCompletion {
+            content: output,
+            usage: json.usage.map(TokenUsage::from),
+            tool_calls: Vec::new(),
+        })
+    }
+
+    async fn complete_stream(
+        &self,
+        request: &ChatRequest,
+        progress: &watch::Sender<String>,
+    ) -> Result<ChatCompletion> {
+        let mut response = self.anthropic_post(request, true).await?;
+
+        let mut decoder = LineDecoder::default();
+        let mut output = String::new();
+        let mut usage = TokenUsage::default();
+        'stream: while let Some(chunk) = response.chunk().await.map_err(ApiError::from)? {
+            for data in decoder.push_sse(&chunk) {
+                trace!("Anthropic stream chunk: {data}");
+                let event: EventAnthropic = serde_json::from_str(&data)?;
+                match event.kind.as_str() {
+                    // Prompt tokens are counted at the start, completion tokens at the end
+                    "message_start" => {
+                        if let Some(start) = event.message.and_then(|m| m.usage) {
+                            usage.prompt_tokens = start.input_tokens;
+                        }
+                    }
+                    "message_delta" => {
+                        if let Some(delta) = event.usage {
+                            usage.completion_tokens = delta.output_tokens;
+                        }
+                    }
+                    "content_block_delta" => {
+                        let text = event.delta.and_then(|d| d.text).unwrap_or_default();
+                        if !text.is_empty() {
+                            output.push_str(&text);
+                            progress.send_replace(output.clone());
+                        }
+                    }
+                    "message_stop" => break 'stream,
+                    "error" => {
+                        let error = ApiError::from_response(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            &HeaderMap::new(),
+                            &data,
+                        );
+                        return Err(error.into());
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if output.is_empty() {
+            return Err(anyhow!("No output found."));
+        }
+
+        Ok(ChatCompletion {
+            content: output,
+            usage: Some(usage),
+            tool_calls: Vec::new(),
+        })
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct RequestAnthropic {
+    model: String,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    system: String,
+    messages: Vec<MessageAnthropic>,
+    stream: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct MessageAnthropic {
+    role: String,
+    content: ContentAnthropic,
+}
+
+/// Plain text, or content blocks when there are pictures
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum ContentAnthropic {
+    Text(String),
+    Blocks(Vec<BlockAnthropic>),
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockAnthropic {
+    Image { source: ImageSourceAnthropic },
+    Text { text: String },
+}
+
+#[derive(Serialize, Debug)]
+struct ImageSourceAnthropic {
+    #[serde(rename = "type")]
+    kind: String,
+    media_type: String,
+    data: String,
+}
+
+impl From<&MessageChat> for MessageAnthropic {
+    fn from(message: &MessageChat) -> Self {
+        let content = if message.images.is_empty() {
+            ContentAnthropic::Text(message.content.clone())
+        } else {
+            // Pictures go before the question about them
+            let mut blocks: Vec<BlockAnthropic> = message
+                .images
+                .iter()
+                .filter_map(|image| {
+                    Some(BlockAnthropic::Image {
+                        source: ImageSourceAnthropic {
+                            kind: "base64".to_string(),
+                            media_type: image.mime_type.clone(),
+                            data: encode_image(image)?,
+                        },
+                    })
+                })
+                .collect();
+            blocks.push(BlockAnthropic::Text {
+                text: message.content.clone(),
+            });
+            ContentAnthropic::Blocks(blocks)
+        };
+        MessageAnthropic {
+            role: message.role.clone(),
+            content,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ResponseAnthropic {
+    content: Vec<ContentBlockAnthropic>,
+    usage: Option<UsageAnthropic>,
+}
+
+#[derive(Deserialize, Debug)]
+struct UsageAnthropic {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+impl From<UsageAnthropic> for TokenUsage {
+    fn from(usage: UsageAnthropic) -> Self {
+        TokenUsage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ContentBlockAnthropic {
+    text: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EventAnthropic {
+    #[serde(rename = "type")]
+    kind: String,
+    delta: Option<DeltaAnthropic>,
+    message: Option<MessageStartAnthropic>,
+    usage: Option<UsageAnthropic>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageStartAnthropic {
+    usage: Option<UsageAnthropic>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DeltaAnthropic {
+    text: Option<String>,
+}
@@ -0,0 +1,288 @@
+use super::retry::server_delay;
+
+use anyhow::Result;
+
+use reqwest::header::HeaderMap;
+use reqwest::{Response, StatusCode};
+
+use serde_derive::Deserialize;
+
+use std::fmt;
+use std::time::Duration;
+
+/// Why a request to an AI backend failed, parsed from the HTTP status and error body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    Auth(String),
+    RateLimit {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    QuotaExceeded(String),
+    ContextLengthExceeded(String),
+    ContentPolicy(String),
+    Server {
+        status: u16,
+        message: String,
+    },
+    Network(String),
+    /// Any other rejected request, such as an unknown model
+    Other {
+        status: u16,
+        message: String,
+    },
+}
+
+impl ApiError {
+    /// Classify an error response of OpenAI, Anthropic or Ollama style APIs
+    #[must_use]
+    pub fn from_response(status: StatusCode, headers: &HeaderMap, body: &str) -> Self {
+        let (message, kind, code) = match serde_json::from_str::<ErrorBody>(body) {
+            Ok(ErrorBody {
+                error:
+                    ErrorDetail::Object {
+                        message,
+                        kind,
+                        code,
+                    },
+            }) => (message, kind.unwrap_or_default(), code.unwrap_or_default()),
+            Ok(ErrorBody {
+                error: ErrorDetail::Message(message),
+            }) => (message, String::new(), String::new()),
+            Err(_) => (body.trim().to_string(), String::new(), String::new()),
+        };
+        let message = if message.is_empty() {
+            status.to_string()
+        } else {
+            message
+        };
+        let lower = message.to_lowercase();
+
+        if status == StatusCode::UNAUTHORIZED
+            || status == StatusCode::FORBIDDEN
+            || code == "invalid_api_key"
+            || kind == "authentication_error"
+            || kind == "permission_error"
+        {
+            ApiError::Auth(message)
+        } else if code == "insufficient_quota"
+            || kind == "insufficient_quota"
+            || lower.contains("credit balance")
+        {
+            // OpenAI reports an empty account with the same status as a rate limit
+            ApiError::QuotaExceeded(message)
+        } else if code == "context_length_exceeded"
+            || lower.contains("maximum context length")
+            || lower.contains("prompt is too long")
+            || lower.contains("context window")
+        {
+            ApiError::ContextLengthExceeded(message)
+        } else if code == "content_policy_violation"
+            || code == "content_filter"
+            || lower.contains("safety system")
+        {
+            ApiError::ContentPolicy(message)
+        } else if status == StatusCode::TOO_MANY_REQUESTS || kind == "rate_limit_error" {
+            ApiError::RateLimit {
+                message,
+                retry_after: server_delay(headers),
+            }
+        } else if status.is_server_error() || kind == "overloaded_error" || kind == "api_error" {
+            ApiError::Server {
+                status: status.as_u16(),
+                message,
+            }
+        } else {
+            ApiError::Other {
+                status: status.as_u16(),
+                message,
+            }
+        }
+    }
+
+    /// A friendly explanation for the chat, with what to do about it
+    #[must_use]
+    pub fn user_message(&self) -> String {
+        match self {
+            ApiError::Auth(_) => "The bot could not log in to the AI provider, an admin needs to check the API key.".to_string(),
+            ApiError::RateLimit {
+                retry_after: Some(delay),
+                ..
+            } => format!(
+                "The AI provider is rate limiting the bot, please try again in {} seconds.",
+                delay.as_secs().max(1)
+            ),
+            ApiError::RateLimit { .. } => "The AI provider is rate limiting the bot, please try again in a minute.".to_string(),
+            ApiError::QuotaExceeded(_) => "The bot's API account is out of credit or over its quota, an admin needs to check the billing.".to_string(),
+            ApiError::ContextLengthExceeded(_) => "This conversation has grown too long for the model, use /chatpurge to start a new one.".to_string(),
+            ApiError::ContentPolicy(_) => "The AI provider refused this request because of its content policy, try rephrasing it.".to_string(),
+            ApiError::Server { status, .. } => format!("The AI provider is having problems (HTTP {status}), please try again later."),
+            ApiError::Network(_) => "The bot could not reach the AI provider, please try again later.".to_string(),
+            ApiError::Other { message, .. } => format!("The AI provider rejected the request: {message}"),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Auth(message) => write!(f, "authentication failed: {message}"),
+            ApiError::RateLimit { message, .. } => write!(f, "rate limited: {message}"),
+            ApiError::QuotaExceeded(message) => write!(f, "quota exceeded: {message}"),
+            ApiError::ContextLengthExceeded(message) => {
+                write!(f, "context length exceeded: {message}")
+            }
+            ApiError::ContentPolicy(message) => write!(f, "content policy violation: {message}"),
+            ApiError::Server { status, message } => write!(f, "server error {status}: {message}"),
+            ApiError::Network(message) => write!(f, "network error: {message}"),
+            ApiError::Other { status, message } => write!(f, "request failed {status}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(error: reqwest::Error) -> Self {
+        ApiError::Network(error.to_string())
+    }
+}
+
+/// Pass successful responses through and turn the others into an `ApiError`
+/// # Errors
+/// The response has an error status
+pub async fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let headers = response.headers().clone();
+    let body = response.text().await.map_err(ApiError::from)?;
+    Err(ApiError::from_response(status, &headers, &body).into())
+}
+
+// Error bodies are `{"error": {"message", "type", "code"}}` or `{"error": "message"}`
+#[derive(Deserialize, Debug)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ErrorDetail {
+    Object {
+        #[serde(default)]
+        message: String,
+        #[serde(rename = "type")]
+        kind: Option<String>,
+        code: Option<String>,
+    },
+    Message(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(status: u16, body: &str) -> ApiError {
+        ApiError::from_response(
+            StatusCode::from_u16(status).unwrap(),
+            &HeaderMap::new(),
+            body,
+        )
+    }
+
+    #[test]
+    fn test_openai_errors() {
+        assert!(matches!(
+            parse(
+                401,
+                r#"{"error":{"message":"Incorrect API key provided","type":"invalid_request_error","code":"invalid_api_key"}}"#
+            ),
+            ApiError::Auth(_)
+        ));
+        assert!(matches!(
+            parse(
+                429,
+                r#"{"error":{"message":"You exceeded your current quota","type":"insufficient_quota","code":"insufficient_quota"}}"#
+            ),
+            ApiError::QuotaExceeded(_)
+        ));
+        assert!(matches!(
+            parse(
+                429,
+                r#"{"error":{"message":"Rate limit reached","type":"requests","code":"rate_limit_exceeded"}}"#
+            ),
+            ApiError::RateLimit { .. }
+        ));
+        assert!(matches!(
+            parse(
+                400,
+                r#"{"error":{"message":"This model's maximum context length is 128000 tokens","type":"invalid_request_error","code":"context_length_exceeded"}}"#
+            ),
+            ApiError::ContextLengthExceeded(_)
+        ));
+        assert!(matches!(
+            parse(
+                400,
+                r#"{"error":{"message":"Your request was rejected as a result of our safety system.","type":"invalid_request_error","code":"content_policy_violation"}}"#
+            ),
+            ApiError::ContentPolicy(_)
+        ));
+        assert_eq!(
+            parse(
+                404,
+                r#"{"error":{"message":"The model `gpt-9` does not exist","type":"invalid_request_error","code":"model_not_found"}}"#
+            ),
+            ApiError::Other {
+                status: 404,
+                message: "The model `gpt-9` does not exist".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_other_provider_errors() {
+        assert!(matches!(
+            parse(
+                529,
+                r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#
+            ),
+            ApiError::Server { status: 529, .. }
+        ));
+        assert!(matches!(
+            parse(
+                400,
+                r#"{"type":"error","error":{"type":"invalid_request_error","message":"prompt is too long: 210000 tokens > 200000 maximum"}}"#
+            ),
+            ApiError::ContextLengthExceeded(_)
+        ));
+        assert_eq!(
+            parse(404, r#"{"error":"model \"llama9\" not found"}"#),
+            ApiError::Other {
+                status: 404,
+                message: "model \"llama9\" not found".to_string()
+            }
+        );
+        assert!(matches!(
+            parse(502, "<html>Bad Gateway</html>"),
+            ApiError::Server { status: 502, .. }
+        ));
+    }
+
+    #[test]
+    fn test_rate_limit_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", "7".parse().unwrap());
+        let error = ApiError::from_response(StatusCode::TOO_MANY_REQUESTS, &headers, "");
+        assert_eq!(
+            error,
+            ApiError::RateLimit {
+                message: "429 Too Many Requests".to_string(),
+                retry_after: Some(Duration::from_secs(7))
+            }
+        );
+        assert!(error.user_message().contains("7 seconds"));
+    }
+}
@@ -1,4 +1,5 @@
 use super::config_manager::ConfigManager;
+use super::history_store::{open_store, HistoryStore};
 
 use anyhow::Result;
 
@@ -6,19 +7,45 @@ use log::{debug, warn};
 
 use serde_derive::{Deserialize, Serialize};
 
-use std::fs::{create_dir, File, OpenOptions};
-use std::io::prelude::*;
-use std::path::Path;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatHistory {
     pub messages: Vec<MessageChat>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
 pub struct MessageChat {
     pub role: String,
     pub content: String,
+    /// Telegram message this entry was sent as or received in, used to follow reply threads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<i32>,
+    /// Pictures sent along with the text
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImageRef>,
+    /// Tools the assistant called instead of answering
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The call a `tool` message holds the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+/// A call of a tool by the model, `arguments` is the JSON object as the model wrote it
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
+/// A picture attached to a message, the history refers to the file instead of embedding it
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    pub path: String,
+    pub mime_type: String,
 }
 
 pub enum Role {
@@ -27,6 +54,83 @@ pub enum Role {
     Assistant,
 }
 
+/// Prefix of the system message that holds a summary of folded conversation turns
+pub const SUMMARY_MARKER: &str = "[Summary of the earlier conversation]";
+
+// Fixed cost of every message on top of its content, covers the role and separators
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+// Rough cost of an attached picture, vision models charge by resolution
+const IMAGE_TOKENS: usize = 800;
+
+/// Rough token count of a piece of text, English averages about four characters per token
+#[must_use]
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+impl MessageChat {
+    /// A message with only a role and text, set the other fields with struct update syntax
+    #[must_use]
+    pub fn new(role: &str, content: &str) -> Self {
+        MessageChat {
+            role: role.to_string(),
+            content: content.to_string(),
+            ..MessageChat::default()
+        }
+    }
+
+    /// Estimated tokens this message costs when sent to the API
+    #[must_use]
+    pub fn estimated_tokens(&self) -> usize {
+        let calls: usize = self
+            .tool_calls
+            .iter()
+            .map(|call| estimate_tokens(&call.name) + estimate_tokens(&call.arguments))
+            .sum();
+        estimate_tokens(&self.content)
+            + calls
+            + MESSAGE_OVERHEAD_TOKENS
+            + self.images.len() * IMAGE_TOKENS
+    }
+
+    /// Whether this message is a summary made by folding older turns
+    #[must_use]
+    pub fn is_summary(&self) -> bool {
+        self.role == "system" && self.content.starts_with(SUMMARY_MARKER)
+    }
+}
+
+/// What was left out when fitting a history into a token budget
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TrimReport {
+    pub dropped_messages: usize,
+    pub dropped_tokens: usize,
+    pub kept_tokens: usize,
+}
+
+/// The entries one exchange wrote to a history, to tag them with the Telegram messages of
+/// the exchange later
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exchange {
+    /// Position of the question, `None` for a new answer to a question that is tagged already
+    pub question: Option<usize>,
+    /// Position of the answer
+    pub answer: usize,
+    /// Text of the answer, to make sure the entry at `answer` is still the same one
+    pub answer_text: String,
+}
+
+// One lock per chat, updates of the same history are applied one at a time
+static CHAT_LOCKS: LazyLock<Mutex<HashMap<String, Arc<Mutex<()>>>>> = LazyLock::new(Mutex::default);
+
+fn chat_lock(chat_id: &str) -> Arc<Mutex<()>> {
+    let mut locks = CHAT_LOCKS.lock().unwrap_or_else(PoisonError::into_inner);
+    // Locks only the map holds are not in use, nobody can be waiting on them
+    locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+    Arc::clone(locks.entry(chat_id.to_string()).or_default())
+}
+
 // Default config values
 impl Default for ChatHistory {
     fn default() -> Self {
@@ -35,10 +139,7 @@ impl Default for ChatHistory {
             Err(e) => panic!("Failed to get config with {e}"),
         };
         ChatHistory {
-            messages: vec![MessageChat {
-                role: "system".to_string(),
-                content: config.chat_base_prompt,
-            }],
+            messages: vec![MessageChat::new("system", &config.chat_base_prompt)],
         }
     }
 }
@@ -46,45 +147,111 @@ impl Default for ChatHistory {
 impl ChatHistory {
     /// Process a new message, will create a new chat or add to an existing one
     /// # Errors
-    /// OS file write errors
+    /// History store errors
     pub fn new(chat_id: &str) -> Result<Self> {
-        let serialized_data = match ChatHistory::read_file(chat_id) {
-            Ok(data) => data,
+        let store = open_store()?;
+        let lock = chat_lock(chat_id);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        Self::load_or_create(store.as_ref(), chat_id)
+    }
+
+    /// The history of `chat_id` if there is one, unlike `new` nothing is created
+    /// # Errors
+    /// History store errors
+    pub fn find(chat_id: &str) -> Result<Option<Self>> {
+        let store = open_store()?;
+        let lock = chat_lock(chat_id);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        store.load(chat_id)
+    }
+
+    /// Chat IDs of the stored histories that start with `prefix`
+    /// # Errors
+    /// History store errors
+    pub fn list(prefix: &str) -> Result<Vec<String>> {
+        open_store()?.list(prefix)
+    }
+
+    /// Remove the history of `chat_id` from the store
+    /// # Errors
+    /// History store errors
+    pub fn delete(chat_id: &str) -> Result<()> {
+        let store = open_store()?;
+        let lock = chat_lock(chat_id);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        store.delete(chat_id)
+    }
+
+    /// Only call while holding the lock of `chat_id`
+    fn load_or_create(store: &dyn HistoryStore, chat_id: &str) -> Result<Self> {
+        let serialized_data = match store.load(chat_id) {
+            Ok(Some(data)) => return Ok(data),
+            Ok(None) => ChatHistory::default(),
             Err(error) => {
-                warn!("Using default values due to error in reading history file: {error}");
+                warn!("Using default values due to error in reading history: {error}");
                 ChatHistory::default()
             }
         };
 
-        serialized_data.write_file(chat_id)?;
+        store.save(chat_id, &serialized_data)?;
 
         Ok(serialized_data)
     }
 
     /// Add an entry to the selected `chat_id` with the given role
-    /// Also writes to the history file
+    /// Also writes to the history store
     /// # Errors
-    /// OS file write errors
-    pub fn add_entry(mut self, chat_id: &str, role: &Role, content: &str) -> Result<Self> {
+    /// History store errors
+    pub fn add_entry(self, chat_id: &str, role: &Role, content: &str) -> Result<Self> {
+        self.add_entry_with_images(chat_id, role, content, Vec::new())
+    }
+
+    /// Add an entry with pictures to the selected `chat_id` with the given role
+    /// Also writes to the history store
+    /// # Errors
+    /// History store errors
+    pub fn add_entry_with_images(
+        self,
+        chat_id: &str,
+        role: &Role,
+        content: &str,
+        images: Vec<ImageRef>,
+    ) -> Result<Self> {
         let role_string = match role {
-            Role::User => "user".to_string(),
-            Role::System => "system".to_string(),
-            Role::Assistant => "assistant".to_string(),
+            Role::User => "user",
+            Role::System => "system",
+            Role::Assistant => "assistant",
         };
 
-        self.messages.push(MessageChat {
-            role: role_string,
-            content: content.to_string(),
-        });
+        self.add_message(
+            chat_id,
+            MessageChat {
+                images,
+                ..MessageChat::new(role_string, content)
+            },
+        )
+    }
+
+    /// Add a message as it is to the selected `chat_id`, such as tool calls and their results
+    /// Also writes to the history store
+    /// # Errors
+    /// History store errors
+    pub fn add_message(mut self, chat_id: &str, message: MessageChat) -> Result<Self> {
+        let store = open_store()?;
+        let lock = chat_lock(chat_id);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
 
-        self.write_file(chat_id)?;
+        // Another update may have landed since this copy was read
+        self = Self::load_or_create(store.as_ref(), chat_id)?;
+        store.append(chat_id, &message)?;
+        self.messages.push(message);
         Ok(self)
     }
 
     /// Wipes a chat history, if the user provides a system prompt it will be used otherwise it
     /// will use the system prompt in the system config file
     /// # Errors
-    /// OS file write errors
+    /// History store errors
     pub fn purge(mut self, chat_id: &str, prompt: &str) -> Result<Self> {
         let init_prompt = if prompt.is_empty() {
             let config = ConfigManager::new()?;
@@ -95,50 +262,527 @@ impl ChatHistory {
 
         debug!("Init prompt: {init_prompt}");
 
-        self.messages = vec![MessageChat {
-            role: "system".to_string(),
-            content: init_prompt.to_string(),
-        }];
+        self.messages = vec![MessageChat::new("system", &init_prompt)];
 
         debug!("Post-purge struct: {self:?}");
 
-        self.write_file(chat_id)?;
+        let store = open_store()?;
+        let lock = chat_lock(chat_id);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        store.save(chat_id, &self)?;
         Ok(self)
     }
 
-    fn read_file(chat_id: &str) -> Result<Self> {
-        // If the chat history directory does not exist make it
-        if !Path::new("./chat-history").is_dir() {
-            create_dir("./chat-history")?;
+    /// Record the Telegram messages of an exchange on the entries it wrote. The entries are
+    /// left alone when the history changed so that they are no longer at those positions.
+    /// # Errors
+    /// History store errors
+    pub fn tag_exchange(
+        chat_id: &str,
+        exchange: &Exchange,
+        user_message_id: i32,
+        bot_message_id: i32,
+    ) -> Result<()> {
+        let store = open_store()?;
+        let lock = chat_lock(chat_id);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut history = Self::load_or_create(store.as_ref(), chat_id)?;
+        let answer_found = history.messages.get(exchange.answer).is_some_and(|m| {
+            m.role == "assistant"
+                && m.tool_calls.is_empty()
+                && m.message_id.is_none()
+                && m.content == exchange.answer_text
+        });
+        let question_found = exchange.question.is_none_or(|question| {
+            question < exchange.answer
+                && history
+                    .messages
+                    .get(question)
+                    .is_some_and(|m| m.role == "user" && m.message_id.is_none())
+        });
+        if !answer_found || !question_found {
+            warn!("History {chat_id} changed during the exchange, its messages are not tagged");
+            return Ok(());
         }
-        let user_history_file = format!("chat-history/{chat_id}-history.json");
-        let path = Path::new(&user_history_file);
 
-        let mut file = File::open(path)?;
+        history.messages[exchange.answer].message_id = Some(bot_message_id);
+        if let Some(question) = exchange.question {
+            history.messages[question].message_id = Some(user_message_id);
+        }
 
-        let mut json_string = String::new();
-        file.read_to_string(&mut json_string)?;
+        store.save(chat_id, &history)?;
+        Ok(())
+    }
 
-        let serialized_data: ChatHistory = serde_json::from_str(&json_string)?;
+    /// Remove the answer to the last question, with the tool calls made for it, and return the
+    /// history that is left to answer again
+    /// # Errors
+    /// History store errors
+    pub fn pop_answer(chat_id: &str) -> Result<Self> {
+        Self::rewrite(chat_id, |history| {
+            if let Some(question) = history.messages.iter().rposition(|m| m.role == "user") {
+                history.messages.truncate(question + 1);
+            }
+        })
+    }
 
-        Ok(serialized_data)
+    /// Remove the last question and everything after it, as if it was never asked.
+    /// Returns the removed entries, none if there was no question.
+    /// # Errors
+    /// History store errors
+    pub fn forget_turn(chat_id: &str) -> Result<Vec<MessageChat>> {
+        let mut removed = Vec::new();
+        Self::rewrite(chat_id, |history| {
+            if let Some(question) = history.messages.iter().rposition(|m| m.role == "user") {
+                removed = history.messages.split_off(question);
+            }
+        })?;
+        Ok(removed)
     }
 
-    fn write_file(&self, chat_id: &str) -> Result<()> {
-        let user_history_file = format!("chat-history/{chat_id}-history.json");
-        let path = Path::new(&user_history_file);
+    /// Replace the text of the question received in `message_id` and drop its answer, so it can
+    /// be answered again. Only the last question can be changed, returns false for older ones.
+    /// # Errors
+    /// History store errors
+    pub fn rewrite_question(chat_id: &str, message_id: i32, content: &str) -> Result<bool> {
+        let mut rewritten = false;
+        Self::rewrite(chat_id, |history| {
+            let last_question = history.messages.iter().rposition(|m| m.role == "user");
+            let Some(question) = history.position_of(message_id) else {
+                return;
+            };
+            if last_question == Some(question) {
+                history.messages[question].content = content.to_string();
+                history.messages.truncate(question + 1);
+                rewritten = true;
+            }
+        })?;
+        Ok(rewritten)
+    }
 
-        let json_string = serde_json::to_string_pretty(&self)?;
+    /// Add `text` to the end of the last answer and return the whole answer
+    /// # Errors
+    /// History store errors
+    pub fn extend_answer(chat_id: &str, text: &str) -> Result<String> {
+        let mut answer = String::new();
+        Self::rewrite(chat_id, |history| {
+            if let Some(last) = history
+                .messages
+                .last_mut()
+                .filter(|m| m.role == "assistant")
+            {
+                let separator = if last.content.ends_with(char::is_whitespace)
+                    || text.starts_with(char::is_whitespace)
+                {
+                    ""
+                } else {
+                    " "
+                };
+                last.content = format!("{}{separator}{text}", last.content);
+                answer.clone_from(&last.content);
+            }
+        })?;
+        Ok(answer)
+    }
 
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(false)
-            .truncate(true)
-            .open(path)?;
+    fn rewrite(chat_id: &str, change: impl FnOnce(&mut Self)) -> Result<Self> {
+        let store = open_store()?;
+        let lock = chat_lock(chat_id);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
 
-        file.write_all(json_string.as_bytes())?;
+        let mut history = Self::load_or_create(store.as_ref(), chat_id)?;
+        change(&mut history);
+        store.save(chat_id, &history)?;
+        Ok(history)
+    }
 
-        Ok(())
+    /// Index of the entry that was sent as or received in a Telegram message
+    #[must_use]
+    pub fn position_of(&self, message_id: i32) -> Option<usize> {
+        self.messages
+            .iter()
+            .position(|m| m.message_id == Some(message_id))
+    }
+
+    /// Start the history `to` as a copy of `from` up to and including the entry of
+    /// `message_id`. Returns false, and copies nothing, if `from` has no such entry.
+    /// # Errors
+    /// History store errors
+    pub fn fork(from: &str, to: &str, message_id: i32) -> Result<bool> {
+        let store = open_store()?;
+        let source = {
+            let lock = chat_lock(from);
+            let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+            Self::load_or_create(store.as_ref(), from)?
+        };
+        let Some(end) = source.position_of(message_id) else {
+            return Ok(false);
+        };
+
+        let branch = ChatHistory {
+            messages: source.messages[..=end].to_vec(),
+        };
+        let lock = chat_lock(to);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        store.save(to, &branch)?;
+        Ok(true)
+    }
+
+    /// Messages that should be folded into a summary once the history holds more than
+    /// `trigger` messages besides the base prompt. Covers the previous summary, if any, plus at
+    /// least `count` of the oldest messages, extended so that a turn is never split.
+    #[must_use]
+    pub fn foldable(&self, trigger: usize, count: usize) -> Option<&[MessageChat]> {
+        let start = usize::from(self.messages.first().is_some_and(|m| !m.is_summary()));
+        let turns = self.messages.len() - start;
+        if count == 0 || turns <= trigger.max(count) {
+            return None;
+        }
+
+        let first_turn = start + usize::from(self.messages[start].is_summary());
+        let mut end = (first_turn + count).min(self.messages.len() - 1);
+        while end < self.messages.len() - 1 && self.messages[end].role != "user" {
+            end += 1;
+        }
+
+        Some(&self.messages[start..end]).filter(|folded| folded.len() > 1)
+    }
+
+    /// Replace the messages returned by `foldable` with a single summary message.
+    /// Nothing is folded if those messages changed since this copy of the history was read.
+    /// # Errors
+    /// History store errors
+    pub fn fold_summary(mut self, chat_id: &str, folded: usize, summary: &str) -> Result<Self> {
+        let start = usize::from(self.messages.first().is_some_and(|m| !m.is_summary()));
+        let end = (start + folded).min(self.messages.len());
+
+        let store = open_store()?;
+        let lock = chat_lock(chat_id);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Summarizing takes a while, new turns may have been added or the chat purged meanwhile
+        let current = Self::load_or_create(store.as_ref(), chat_id)?;
+        if current.messages.get(..end) != Some(&self.messages[..end]) {
+            warn!("History of {chat_id} changed while summarizing, not folding");
+            return Ok(current);
+        }
+        self = current;
+
+        self.messages.splice(
+            start..end,
+            [MessageChat::new(
+                "system",
+                &format!("{SUMMARY_MARKER}\n{summary}"),
+            )],
+        );
+
+        debug!("Folded {folded} messages of {chat_id} into a summary");
+
+        store.save(chat_id, &self)?;
+        Ok(self)
+    }
+
+    /// Select the messages to send within an estimated `budget` of tokens.
+    /// The leading system messages and the newest message are always kept, the oldest turns are
+    /// dropped first and replaced with a short note so the model knows context is missing.
+    /// The stored history is left untouched.
+    #[must_use]
+    pub fn within_budget(&self, budget: usize) -> (Vec<MessageChat>, TrimReport) {
+        // The base prompt and any conversation summary lead the history
+        let leading = self
+            .messages
+            .iter()
+            .take_while(|m| m.role == "system")
+            .count();
+        let (system, turns) = self.messages.split_at(leading);
+
+        let total: usize = self
+            .messages
+            .iter()
+            .map(MessageChat::estimated_tokens)
+            .sum();
+        if total <= budget || turns.len() <= 1 {
+            let report = TrimReport {
+                kept_tokens: total,
+                ..TrimReport::default()
+            };
+            return (self.messages.clone(), report);
+        }
+
+        // Reserve room for the note that replaces the dropped turns
+        let fixed = system
+            .iter()
+            .map(MessageChat::estimated_tokens)
+            .sum::<usize>()
+            + estimate_tokens(&trim_note(turns.len()))
+            + MESSAGE_OVERHEAD_TOKENS;
+
+        // Walk back from the newest message until the budget runs out
+        let mut kept_tokens = fixed;
+        let mut start = turns.len() - 1;
+        kept_tokens += turns[start].estimated_tokens();
+        while start > 0 {
+            let cost = turns[start - 1].estimated_tokens();
+            if kept_tokens + cost > budget {
+                break;
+            }
+            kept_tokens += cost;
+            start -= 1;
+        }
+
+        // Never open the window halfway through a turn
+        while start < turns.len() - 1 && turns[start].role != "user" {
+            kept_tokens -= turns[start].estimated_tokens();
+            start += 1;
+        }
+
+        let dropped = &turns[..start];
+        let report = TrimReport {
+            dropped_messages: dropped.len(),
+            dropped_tokens: dropped.iter().map(MessageChat::estimated_tokens).sum(),
+            kept_tokens,
+        };
+
+        let mut messages: Vec<MessageChat> = system.to_vec();
+        if !dropped.is_empty() {
+            messages.push(MessageChat::new("system", &trim_note(dropped.len())));
+        }
+        messages.extend_from_slice(&turns[start..]);
+
+        (messages, report)
+    }
+}
+
+fn trim_note(dropped: usize) -> String {
+    format!("[{dropped} earlier messages were omitted to fit the context window]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn long_history(turns: usize) -> ChatHistory {
+        let mut messages = vec![MessageChat::new("system", "Base prompt")];
+        for i in 0..turns {
+            messages.push(MessageChat::new(
+                "user",
+                &format!("Question {i} {}", "q".repeat(400)),
+            ));
+            messages.push(MessageChat::new(
+                "assistant",
+                &format!("Answer {i} {}", "a".repeat(400)),
+            ));
+        }
+        ChatHistory { messages }
+    }
+
+    #[test]
+    fn test_parallel_add_entry() {
+        const THREADS: usize = 8;
+        const ENTRIES: usize = 25;
+        let chat_id = "test_parallel_add_entry";
+
+        ChatHistory::new(chat_id)
+            .unwrap()
+            .purge(chat_id, "Parallel test")
+            .unwrap();
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                std::thread::spawn(move || {
+                    for entry in 0..ENTRIES {
+                        ChatHistory::new(chat_id)
+                            .unwrap()
+                            .add_entry(chat_id, &Role::User, &format!("{thread}-{entry}"))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let history = ChatHistory::new(chat_id).unwrap();
+        assert_eq!(history.messages.len(), 1 + THREADS * ENTRIES);
+        for thread in 0..THREADS {
+            for entry in 0..ENTRIES {
+                let content = format!("{thread}-{entry}");
+                assert!(history.messages.iter().any(|m| m.content == content));
+            }
+        }
+    }
+
+    #[test]
+    fn test_rewrite_last_turn() {
+        let chat_id = "test_rewrite_last_turn";
+        let contents = |history: &ChatHistory| -> Vec<String> {
+            history.messages.iter().map(|m| m.content.clone()).collect()
+        };
+        let history = ChatHistory::new(chat_id)
+            .unwrap()
+            .purge(chat_id, "base")
+            .unwrap()
+            .add_entry(chat_id, &Role::User, "question")
+            .unwrap()
+            .add_message(chat_id, MessageChat::new("tool", "42"))
+            .unwrap()
+            .add_entry(chat_id, &Role::Assistant, "The answer is")
+            .unwrap();
+        drop(history);
+
+        assert_eq!(
+            ChatHistory::extend_answer(chat_id, "42.").unwrap(),
+            "The answer is 42."
+        );
+        let history = ChatHistory::pop_answer(chat_id).unwrap();
+        assert_eq!(contents(&history), vec!["base", "question"]);
+
+        let history = ChatHistory::new(chat_id)
+            .unwrap()
+            .add_entry(chat_id, &Role::Assistant, "answer")
+            .unwrap();
+        let mut exchange = Exchange {
+            question: Some(history.messages.len() - 2),
+            answer: history.messages.len() - 1,
+            answer_text: "other answer".to_string(),
+        };
+        // Entries that no longer match the exchange are not tagged
+        ChatHistory::tag_exchange(chat_id, &exchange, 7, 8).unwrap();
+        assert!(!ChatHistory::rewrite_question(chat_id, 7, "edited").unwrap());
+        exchange.answer_text = "answer".to_string();
+        ChatHistory::tag_exchange(chat_id, &exchange, 7, 8).unwrap();
+        assert!(!ChatHistory::rewrite_question(chat_id, 6, "other").unwrap());
+        assert!(ChatHistory::rewrite_question(chat_id, 7, "edited").unwrap());
+        let history = ChatHistory::find(chat_id).unwrap().unwrap();
+        assert_eq!(contents(&history), vec!["base", "edited"]);
+
+        let removed = ChatHistory::forget_turn(chat_id).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].content, "edited");
+        assert_eq!(contents(&ChatHistory::new(chat_id).unwrap()), vec!["base"]);
+        assert!(ChatHistory::forget_turn(chat_id).unwrap().is_empty());
+
+        ChatHistory::delete(chat_id).unwrap();
+    }
+
+    #[test]
+    fn test_chat_locks_are_dropped() {
+        let held = chat_lock("test_chat_locks_held");
+        drop(chat_lock("test_chat_locks_done"));
+        drop(chat_lock("test_chat_locks_other"));
+
+        let locks = CHAT_LOCKS.lock().unwrap();
+        assert!(locks.contains_key("test_chat_locks_held"));
+        assert!(!locks.contains_key("test_chat_locks_done"));
+        drop(locks);
+        drop(held);
+    }
+
+    #[test]
+    fn test_tag_interleaved_exchanges() {
+        let chat_id = "test_tag_interleaved_exchanges";
+        let history = ChatHistory::new(chat_id)
+            .unwrap()
+            .purge(chat_id, "base")
+            .unwrap()
+            .add_entry(chat_id, &Role::User, "first question")
+            .unwrap()
+            .add_entry(chat_id, &Role::User, "second question")
+            .unwrap()
+            .add_entry(chat_id, &Role::Assistant, "second answer")
+            .unwrap()
+            .add_entry(chat_id, &Role::Assistant, "first answer")
+            .unwrap();
+        drop(history);
+
+        // The first exchange is tagged after the second one was written
+        let first = Exchange {
+            question: Some(1),
+            answer: 4,
+            answer_text: "first answer".to_string(),
+        };
+        ChatHistory::tag_exchange(chat_id, &first, 10, 20).unwrap();
+        let history = ChatHistory::new(chat_id).unwrap();
+        let ids: Vec<Option<i32>> = history.messages.iter().map(|m| m.message_id).collect();
+        assert_eq!(ids, vec![None, Some(10), None, None, Some(20)]);
+
+        ChatHistory::delete(chat_id).unwrap();
+    }
+
+    #[test]
+    fn test_foldable_below_trigger() {
+        let history = long_history(4);
+        assert!(history.foldable(10, 4).is_none());
+    }
+
+    #[test]
+    fn test_foldable_whole_turns() {
+        let history = long_history(10);
+        let folded = history.foldable(10, 5).unwrap();
+        assert_eq!(folded.len(), 6);
+        assert_eq!(folded[0].role, "user");
+        assert_eq!(folded[5].role, "assistant");
+    }
+
+    #[test]
+    fn test_foldable_includes_previous_summary() {
+        let mut history = long_history(10);
+        history.messages.insert(
+            1,
+            MessageChat::new("system", &format!("{SUMMARY_MARKER}\nEarlier facts")),
+        );
+        let folded = history.foldable(10, 4).unwrap();
+        assert!(folded[0].is_summary());
+        assert_eq!(folded.len(), 5);
+    }
+
+    #[test]
+    fn test_within_budget_keeps_summary() {
+        let mut history = long_history(10);
+        history.messages.insert(
+            1,
+            MessageChat::new("system", &format!("{SUMMARY_MARKER}\nEarlier facts")),
+        );
+        let (messages, report) = history.within_budget(600);
+        assert!(report.dropped_messages > 0);
+        assert!(messages[1].is_summary());
+    }
+
+    #[test]
+    fn test_within_budget_untouched() {
+        let history = long_history(2);
+        let (messages, report) = history.within_budget(100_000);
+        assert_eq!(messages.len(), history.messages.len());
+        assert_eq!(report.dropped_messages, 0);
+    }
+
+    #[test]
+    fn test_within_budget_drops_oldest_turns() {
+        let history = long_history(10);
+        let (messages, report) = history.within_budget(600);
+
+        assert!(report.dropped_messages > 0);
+        assert!(report.kept_tokens <= 600);
+        assert_eq!(messages[0].content, "Base prompt");
+        assert!(messages[1].content.contains("omitted"));
+        assert_eq!(messages[2].role, "user");
+        assert_eq!(
+            messages.last().unwrap().content,
+            history.messages.last().unwrap().content
+        );
+        assert_eq!(
+            messages.len() - 2 + report.dropped_messages,
+            history.messages.len() - 1
+        );
+    }
+
+    #[test]
+    fn test_within_budget_keeps_newest_message() {
+        let history = long_history(3);
+        let (messages, report) = history.within_budget(10);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(report.dropped_messages, 5);
     }
 }
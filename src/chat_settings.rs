@@ -0,0 +1,111 @@
+use super::config_manager::ConfigManager;
+
+use anyhow::Result;
+
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Mutex, PoisonError};
+
+// Updates from concurrent commands must not overwrite each other
+static SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Behavior chosen for one chat, settings that are not set follow the config file
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ChatSettings {
+    /// Answer messages without the /chat prefix
+    pub auto_reply: Option<bool>,
+    /// Also send answers as voice notes
+    pub voice_replies: Option<bool>,
+    /// Named conversation that messages continue, the main one if not set
+    pub active_conversation: Option<String>,
+    /// Names of the conversations started with /new
+    pub conversations: Vec<String>,
+    /// System prompts saved with /persona save, by name
+    pub personas: HashMap<String, String>,
+}
+
+/// The settings of every chat, kept in one JSON file
+pub struct ChatSettingsStore {
+    path: PathBuf,
+}
+
+impl ChatSettingsStore {
+    #[must_use]
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// The settings file named in the config file
+    /// # Errors
+    /// Config file errors
+    pub fn open() -> Result<Self> {
+        let config = ConfigManager::new()?;
+        Ok(Self::new(Path::new(&config.conversation.settings_path)))
+    }
+
+    fn load(&self) -> Result<HashMap<String, ChatSettings>> {
+        if !self.path.is_file() {
+            return Ok(HashMap::new());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(&self.path)?)?)
+    }
+
+    /// The settings of a chat, the defaults if it never changed any
+    /// # Errors
+    /// OS file read or deserialization errors
+    pub fn get(&self, chat_id: &str) -> Result<ChatSettings> {
+        Ok(self.load()?.remove(chat_id).unwrap_or_default())
+    }
+
+    /// Change the settings of a chat and return the new ones
+    /// # Errors
+    /// OS file errors
+    pub fn update(
+        &self,
+        chat_id: &str,
+        change: impl FnOnce(&mut ChatSettings),
+    ) -> Result<ChatSettings> {
+        let _guard = SETTINGS_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut all = self.load()?;
+        let settings = all.entry(chat_id.to_string()).or_default();
+        change(settings);
+        let settings = settings.clone();
+
+        let temp_path = self
+            .path
+            .with_extension(format!("json.{}.tmp", process::id()));
+        fs::write(&temp_path, serde_json::to_string_pretty(&all)?)?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_and_get() {
+        let path = Path::new("test-chat-settings.json");
+        let store = ChatSettingsStore::new(path);
+        assert_eq!(store.get("1").unwrap(), ChatSettings::default());
+
+        store
+            .update("1", |settings| settings.auto_reply = Some(false))
+            .unwrap();
+        store
+            .update("2", |settings| settings.auto_reply = Some(true))
+            .unwrap();
+        assert_eq!(store.get("1").unwrap().auto_reply, Some(false));
+        assert_eq!(store.get("2").unwrap().auto_reply, Some(true));
+
+        fs::remove_file(path).unwrap();
+    }
+}
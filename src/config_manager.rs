@@ -1,10 +1,14 @@
 use anyhow::Result;
 use log::warn;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::path::Path;
 
+/// Shortest time between two edits of a streamed reply
+pub const MIN_EDIT_INTERVAL_MS: u64 = 250;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigManager {
     pub chat_model: String,
@@ -12,6 +16,633 @@ pub struct ConfigManager {
     pub max_tokens: u32,
     pub image_size: String,
     pub image_model: String,
+    #[serde(default)]
+    pub streaming: StreamingConfig,
+    #[serde(default)]
+    pub context: ContextConfig,
+    #[serde(default)]
+    pub summary: SummaryConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub providers: ProvidersConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub usage: UsageConfig,
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+    #[serde(default)]
+    pub access: AccessConfig,
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub conversation: ConversationConfig,
+    #[serde(default)]
+    pub vision: VisionConfig,
+    #[serde(default)]
+    pub voice: VoiceConfig,
+    #[serde(default)]
+    pub documents: DocumentConfig,
+    #[serde(default)]
+    pub tools: ToolConfig,
+    #[serde(default)]
+    pub persona: PersonaConfig,
+}
+
+/// Controls how chat replies are streamed into Telegram
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StreamingConfig {
+    /// Stream tokens from the API and progressively edit the reply
+    pub enabled: bool,
+    /// Minimum time between two edits of the same reply, Telegram rate limits edits.
+    /// Values below `MIN_EDIT_INTERVAL_MS` are raised to it when the config is loaded.
+    pub edit_interval_ms: u64,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig {
+            enabled: true,
+            edit_interval_ms: 1500,
+        }
+    }
+}
+
+/// Controls how much of a chat history is sent with each request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ContextConfig {
+    /// Estimated tokens of history sent per request, independent of `max_tokens` for the output
+    pub token_budget: usize,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            token_budget: 16_000,
+        }
+    }
+}
+
+/// Controls folding of old conversation turns into a model written summary
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SummaryConfig {
+    pub enabled: bool,
+    /// Summarize once a chat holds more than this many messages besides the base prompt
+    pub trigger_messages: usize,
+    /// How many of the oldest messages are folded into the summary at a time
+    pub fold_messages: usize,
+    /// Model used to write summaries, the chat model is used when empty
+    pub model: String,
+}
+
+impl Default for SummaryConfig {
+    fn default() -> Self {
+        SummaryConfig {
+            enabled: true,
+            trigger_messages: 40,
+            fold_messages: 20,
+            model: String::new(),
+        }
+    }
+}
+
+/// Where chat histories are stored
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub backend: HistoryBackend,
+    /// Directory of the per chat files of the `json` backend
+    pub json_dir: String,
+    /// Database file of the `sqlite` backend
+    pub sqlite_path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryBackend {
+    Json,
+    Sqlite,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            backend: HistoryBackend::Json,
+            json_dir: "chat-history".to_string(),
+            sqlite_path: "chat-history.db".to_string(),
+        }
+    }
+}
+
+/// Which backend serves which model
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ProvidersConfig {
+    /// Backend for models that match none of the routes
+    pub default: ProviderKind,
+    pub routes: Vec<ProviderRoute>,
+    pub ollama_uri: String,
+    pub anthropic_uri: String,
+    pub anthropic_version: String,
+}
+
+/// Send every model whose name starts with `model_prefix` to `provider`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProviderRoute {
+    pub model_prefix: String,
+    pub provider: ProviderKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    OpenAi,
+    Ollama,
+    Anthropic,
+}
+
+impl Default for ProvidersConfig {
+    fn default() -> Self {
+        ProvidersConfig {
+            default: ProviderKind::OpenAi,
+            routes: vec![ProviderRoute {
+                model_prefix: "claude".to_string(),
+                provider: ProviderKind::Anthropic,
+            }],
+            ollama_uri: "http://localhost:11434".to_string(),
+            anthropic_uri: "https://api.anthropic.com/v1".to_string(),
+            anthropic_version: "2023-06-01".to_string(),
+        }
+    }
+}
+
+/// How failed API requests are retried
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Total tries of a request, including the first one
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for each one after
+    pub base_delay_ms: u64,
+    /// Upper limit of a single wait, including waits requested by the server
+    pub max_delay_ms: u64,
+    /// Fraction of a wait that is added at random to spread out retries, from 0 to 1
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 4,
+            base_delay_ms: 500,
+            max_delay_ms: 30_000,
+            jitter: 0.2,
+        }
+    }
+}
+
+/// Where usage is recorded and what it costs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct UsageConfig {
+    /// Ledger file with one JSON record per request, split by month as `usage-2024-01.jsonl`
+    pub path: String,
+    /// Prices by model name, a name also matches the models it is a prefix of
+    pub prices: HashMap<String, ModelPrice>,
+}
+
+/// Price of a model in the currency used for budgets
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ModelPrice {
+    pub prompt_per_million: f64,
+    pub completion_per_million: f64,
+    pub per_image: f64,
+    /// Transcription, per minute of audio
+    pub per_audio_minute: f64,
+    /// Speech, per million characters read out
+    pub per_million_chars: f64,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        let price = |prompt_per_million, completion_per_million, per_image| ModelPrice {
+            prompt_per_million,
+            completion_per_million,
+            per_image,
+            ..ModelPrice::default()
+        };
+        UsageConfig {
+            path: "usage.jsonl".to_string(),
+            prices: HashMap::from([
+                ("gpt-4o".to_string(), price(2.5, 10.0, 0.0)),
+                ("gpt-4o-mini".to_string(), price(0.15, 0.6, 0.0)),
+                ("dall-e-3".to_string(), price(0.0, 0.0, 0.08)),
+                (
+                    "whisper-1".to_string(),
+                    ModelPrice {
+                        per_audio_minute: 0.006,
+                        ..ModelPrice::default()
+                    },
+                ),
+                (
+                    "tts-1".to_string(),
+                    ModelPrice {
+                        per_million_chars: 15.0,
+                        ..ModelPrice::default()
+                    },
+                ),
+                (
+                    "tts-1-hd".to_string(),
+                    ModelPrice {
+                        per_million_chars: 30.0,
+                        ..ModelPrice::default()
+                    },
+                ),
+            ]),
+        }
+    }
+}
+
+/// Spending limits, a limit that is not set is unlimited
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// Limits for each Telegram user
+    pub user: QuotaLimits,
+    /// Limits for each chat
+    pub chat: QuotaLimits,
+    /// Limits for the whole bot
+    pub global: QuotaLimits,
+    /// File with the temporary overrides granted by admins
+    pub overrides_path: String,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            user: QuotaLimits::default(),
+            chat: QuotaLimits::default(),
+            global: QuotaLimits::default(),
+            overrides_path: "quota-overrides.json".to_string(),
+        }
+    }
+}
+
+/// Budgets for a UTC day and a UTC month
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct QuotaLimits {
+    pub daily: Budget,
+    pub monthly: Budget,
+}
+
+/// Upper limits of usage in a period
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Budget {
+    /// Prompt and completion tokens together
+    pub tokens: Option<u64>,
+    pub images: Option<u64>,
+    /// In the currency of the price table
+    pub cost: Option<f64>,
+}
+
+/// Who may use which command
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AccessConfig {
+    /// Telegram user IDs of the bot admins
+    pub admin_ids: Vec<u64>,
+    /// Users that are members, when both allowlists are empty everyone is a member
+    pub allowed_user_ids: Vec<u64>,
+    /// Chats whose every user is a member
+    pub allowed_chat_ids: Vec<i64>,
+    /// Users and chats that the bot ignores completely
+    pub blocked_ids: Vec<i64>,
+    /// Level needed for each command by its lowercase name
+    pub command_levels: HashMap<String, Permission>,
+    /// Level needed for the commands that are not listed
+    pub default_level: Permission,
+}
+
+/// Permission levels, each one includes the ones before it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Public,
+    Member,
+    Admin,
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        AccessConfig {
+            admin_ids: Vec::new(),
+            allowed_user_ids: Vec::new(),
+            allowed_chat_ids: Vec::new(),
+            blocked_ids: Vec::new(),
+            command_levels: HashMap::from([
+                ("help".to_string(), Permission::Public),
+                ("source".to_string(), Permission::Public),
+                ("grant".to_string(), Permission::Admin),
+            ]),
+            default_level: Permission::Member,
+        }
+    }
+}
+
+/// How often commands may be used
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Limits for each command by its lowercase name, commands not listed are unlimited
+    pub commands: HashMap<String, CommandLimit>,
+}
+
+/// Token buckets for one command, a bucket that is not set is unlimited
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CommandLimit {
+    /// Bucket of each user
+    pub user: Option<BucketSpec>,
+    /// Bucket of each chat, shared by everyone in it
+    pub chat: Option<BucketSpec>,
+}
+
+/// A bucket holds up to `capacity` uses and gets one back every `refill_seconds`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BucketSpec {
+    pub capacity: u32,
+    pub refill_seconds: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let bucket = |capacity, refill_seconds| {
+            Some(BucketSpec {
+                capacity,
+                refill_seconds,
+            })
+        };
+        RateLimitConfig {
+            commands: HashMap::from([
+                (
+                    "chat".to_string(),
+                    CommandLimit {
+                        user: bucket(10, 6.0),
+                        chat: bucket(30, 2.0),
+                    },
+                ),
+                (
+                    "retry".to_string(),
+                    CommandLimit {
+                        user: bucket(5, 12.0),
+                        chat: bucket(15, 4.0),
+                    },
+                ),
+                (
+                    "image".to_string(),
+                    CommandLimit {
+                        user: bucket(2, 60.0),
+                        chat: bucket(5, 60.0),
+                    },
+                ),
+                (
+                    "summarize".to_string(),
+                    CommandLimit {
+                        user: bucket(2, 60.0),
+                        chat: bucket(5, 60.0),
+                    },
+                ),
+                (
+                    "say".to_string(),
+                    CommandLimit {
+                        user: bucket(3, 30.0),
+                        chat: bucket(10, 10.0),
+                    },
+                ),
+                (
+                    "gamble".to_string(),
+                    CommandLimit {
+                        user: bucket(3, 20.0),
+                        chat: bucket(6, 10.0),
+                    },
+                ),
+            ]),
+        }
+    }
+}
+
+/// How replies that are too long for one Telegram message are sent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// Replies that need more messages than this are sent as a document, 0 never does
+    pub max_parts: usize,
+    /// Extension of the document, such as `md` or `txt`
+    pub document_extension: String,
+    /// Buttons under answers to regenerate, continue or forget them
+    pub answer_buttons: bool,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            max_parts: 4,
+            document_extension: "md".to_string(),
+            answer_buttons: true,
+        }
+    }
+}
+
+/// Answering messages that are not commands
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ConversationConfig {
+    /// Whether chats that did not choose with /autoreply get answers without /chat
+    pub auto_reply: bool,
+    /// File with the settings each chat chose
+    pub settings_path: String,
+    /// Replies to the bot continue the conversation they reply to instead of the chat's
+    pub threads: bool,
+    /// File that maps the bot's messages to the conversation they belong to
+    pub threads_path: String,
+    /// Named conversations a chat can keep besides its main one
+    pub max_conversations: usize,
+}
+
+impl Default for ConversationConfig {
+    fn default() -> Self {
+        ConversationConfig {
+            auto_reply: true,
+            settings_path: "chat-settings.json".to_string(),
+            threads: true,
+            threads_path: "chat-threads.json".to_string(),
+            max_conversations: 20,
+        }
+    }
+}
+
+/// Photos sent to the chat model
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct VisionConfig {
+    /// Model used when the messages sent include pictures, empty uses `chat_model`
+    pub model: String,
+    /// Directory the photos of the chats are downloaded to. Photos are deleted
+    /// `image_retention_days` after they were sent, history entries that still refer to them
+    /// are then sent without the picture.
+    pub image_dir: String,
+    /// Days photos are kept in `image_dir`, 0 keeps them forever
+    pub image_retention_days: u64,
+    /// Pictures of only this many of the newest messages with pictures are sent again
+    pub history_images: usize,
+    /// Prompt for photos sent without a caption
+    pub default_prompt: String,
+}
+
+impl Default for VisionConfig {
+    fn default() -> Self {
+        VisionConfig {
+            model: String::new(),
+            image_dir: "chat-images".to_string(),
+            image_retention_days: 30,
+            history_images: 2,
+            default_prompt: "What is in this image?".to_string(),
+        }
+    }
+}
+
+/// Voice and audio messages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct VoiceConfig {
+    /// Speech to text model of the `audio/transcriptions` endpoint
+    pub transcription_model: String,
+    /// Reply to voice messages with their transcript
+    pub post_transcript: bool,
+    /// Answer the transcript like a chat message, where text would be answered
+    pub chat_with_transcript: bool,
+    /// Transcribe every voice message in groups, not only the ones replying to the bot
+    pub transcribe_groups: bool,
+    /// Text to speech model of the `audio/speech` endpoint
+    pub speech_model: String,
+    /// Voice the answers are read in
+    pub speech_voice: String,
+    /// Audio format of the answers, Telegram plays `opus` as a voice note
+    pub speech_format: String,
+    /// Longer answers are cut off before they are read out
+    pub speech_max_chars: usize,
+    /// Also answer chat messages with a voice note, unless a chat chooses otherwise
+    pub voice_replies: bool,
+}
+
+impl Default for VoiceConfig {
+    fn default() -> Self {
+        VoiceConfig {
+            transcription_model: "whisper-1".to_string(),
+            post_transcript: true,
+            chat_with_transcript: true,
+            transcribe_groups: true,
+            speech_model: "tts-1".to_string(),
+            speech_voice: "alloy".to_string(),
+            speech_format: "opus".to_string(),
+            speech_max_chars: 4096,
+            voice_replies: false,
+        }
+    }
+}
+
+/// Documents sent to the bot
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DocumentConfig {
+    /// Larger files are not downloaded
+    pub max_file_bytes: u64,
+    /// Text past this many characters of a document is left out
+    pub max_chars: usize,
+    /// How much of a document is added to the chat history
+    pub context_chars: usize,
+    /// Size of the parts a long document is summarized in
+    pub chunk_chars: usize,
+    /// Where the last document of each chat is kept for /summarize
+    pub dir: String,
+}
+
+impl Default for DocumentConfig {
+    fn default() -> Self {
+        DocumentConfig {
+            max_file_bytes: 10_000_000,
+            max_chars: 200_000,
+            context_chars: 20_000,
+            chunk_chars: 20_000,
+            dir: "chat-documents".to_string(),
+        }
+    }
+}
+
+/// Tools the chat model can call
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ToolConfig {
+    pub enabled: bool,
+    /// Built-in tools offered to the model: `current_time`, `calculator` and `roll_dice`
+    pub tools: Vec<String>,
+    /// Rounds of tool calls in one answer, the model has to answer after the last one
+    pub max_rounds: usize,
+}
+
+impl Default for ToolConfig {
+    fn default() -> Self {
+        ToolConfig {
+            enabled: true,
+            tools: vec![
+                "current_time".to_string(),
+                "calculator".to_string(),
+                "roll_dice".to_string(),
+            ],
+            max_rounds: 4,
+        }
+    }
+}
+
+/// Named system prompts that `/persona` starts a conversation with
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PersonaConfig {
+    /// Personas every chat can use, by name, lowercased when the config is loaded
+    pub prompts: HashMap<String, String>,
+    /// Personas each chat can save with `/persona save`
+    pub max_saved: usize,
+}
+
+impl Default for PersonaConfig {
+    fn default() -> Self {
+        PersonaConfig {
+            prompts: HashMap::from([
+                (
+                    "translator".to_string(),
+                    "You are a translator. Translate every message into English, or into the language the user asks for, and only answer with the translation.".to_string(),
+                ),
+                (
+                    "coder".to_string(),
+                    "You are a senior software engineer. Answer with working code in fenced code blocks with the language named, and keep explanations short.".to_string(),
+                ),
+                (
+                    "tutor".to_string(),
+                    "You are a patient tutor. Explain step by step, check what the user already knows and end with a short question to practice.".to_string(),
+                ),
+            ]),
+            max_saved: 20,
+        }
+    }
 }
 
 // Default config values
@@ -19,29 +650,53 @@ impl Default for ConfigManager {
     fn default() -> Self {
         ConfigManager {
             chat_model: "gpt-4o".to_string(),
-            chat_base_prompt: "You are an assistant that is built into a Telegram bot. Only respond with plaintext and if you are writing code begin with CODE-START and end with CODE-END.".to_string(),
+            chat_base_prompt: "You are an assistant that is built into a Telegram bot. Format your answers with Markdown and put code in fenced code blocks with the language named.".to_string(),
             max_tokens: 1024,
             image_size: "1024x1792".to_string(),
             image_model: "dall-e-3".to_string(),
+            streaming: StreamingConfig::default(),
+            context: ContextConfig::default(),
+            summary: SummaryConfig::default(),
+            history: HistoryConfig::default(),
+            providers: ProvidersConfig::default(),
+            retry: RetryConfig::default(),
+            usage: UsageConfig::default(),
+            quotas: QuotaConfig::default(),
+            access: AccessConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            output: OutputConfig::default(),
+            conversation: ConversationConfig::default(),
+            vision: VisionConfig::default(),
+            voice: VoiceConfig::default(),
+            documents: DocumentConfig::default(),
+            tools: ToolConfig::default(),
+            persona: PersonaConfig::default(),
         }
     }
 }
 
 impl ConfigManager {
-    /// Grab values from the system config file
+    /// Grab values from the system config file, writing the defaults if there is none
     /// # Errors
-    /// If there is an OS read and write error
+    /// If there is an OS read and write error or the config file does not parse
     pub fn new() -> Result<Self> {
-        let serialized_data = match Self::read_file(None) {
-            Ok(data) => data,
-            Err(error) => {
-                warn!("Using default values due to error in reading config file: {error}");
-                ConfigManager::default().write_file(None)?;
-                ConfigManager::default()
-            }
-        };
+        Self::load(Path::new("config.json"))
+    }
 
-        Ok(serialized_data)
+    fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            warn!(
+                "No config file at {}, writing the default values",
+                path.display()
+            );
+            let config = ConfigManager::default();
+            config.write_file(Some(path))?;
+            return Ok(config);
+        }
+
+        // A broken file is left for the admin to fix, replacing it with the defaults would
+        // also drop the access lists and open the bot to everyone
+        Self::read_file(Some(path))
     }
 
     fn read_file(path_in: Option<&Path>) -> Result<Self> {
@@ -52,11 +707,27 @@ impl ConfigManager {
         let mut json_string = String::new();
         file.read_to_string(&mut json_string)?;
 
-        let serialized_data: ConfigManager = serde_json::from_str(&json_string)?;
+        let mut serialized_data: ConfigManager = serde_json::from_str(&json_string)?;
+        serialized_data.normalize();
 
         Ok(serialized_data)
     }
 
+    // Bring values that would break the bot back into range, and names into the case they
+    // are looked up in
+    fn normalize(&mut self) {
+        self.streaming.edit_interval_ms = self.streaming.edit_interval_ms.max(MIN_EDIT_INTERVAL_MS);
+        self.retry.jitter = if self.retry.jitter.is_nan() {
+            0.0
+        } else {
+            self.retry.jitter.clamp(0.0, 1.0)
+        };
+        self.persona.prompts = std::mem::take(&mut self.persona.prompts)
+            .into_iter()
+            .map(|(name, prompt)| (name.to_lowercase(), prompt))
+            .collect();
+    }
+
     fn write_file(&self, path_in: Option<&Path>) -> Result<()> {
         let path = path_in.unwrap_or(Path::new("config.json"));
 
@@ -95,6 +766,8 @@ mod tests {
         let path = Path::new("test_config.json");
         let result = ConfigManager::read_file(Some(path)).unwrap();
         assert_eq!(result.chat_base_prompt, "Test prompt");
+        // Sections added after the first release fall back to their defaults
+        assert!(result.streaming.enabled);
         fs::remove_file("test_config.json").unwrap();
     }
 
@@ -120,6 +793,53 @@ mod tests {
         fs::remove_file("test_malformed_config.json").unwrap();
     }
 
+    #[test]
+    fn test_read_file_normalizes_values() {
+        let path = Path::new("test_normalize_config.json");
+        let config = ConfigManager {
+            streaming: StreamingConfig {
+                edit_interval_ms: 0,
+                ..Default::default()
+            },
+            retry: RetryConfig {
+                jitter: 1e300,
+                ..Default::default()
+            },
+            persona: PersonaConfig {
+                prompts: HashMap::from([("Coder".to_string(), "Write code".to_string())]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        config.write_file(Some(path)).unwrap();
+        let read_config = ConfigManager::read_file(Some(path)).unwrap();
+        assert_eq!(read_config.streaming.edit_interval_ms, MIN_EDIT_INTERVAL_MS);
+        assert!((read_config.retry.jitter - 1.0).abs() < f64::EPSILON);
+        assert_eq!(
+            read_config.persona.prompts.get("coder").map(String::as_str),
+            Some("Write code")
+        );
+
+        let mut config = read_config;
+        config.retry.jitter = f64::NAN;
+        config.normalize();
+        assert!(config.retry.jitter.abs() < f64::EPSILON);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_keeps_broken_file() {
+        let path = Path::new("test_load_config.json");
+        let config = ConfigManager::load(path).unwrap();
+        assert_eq!(config.chat_model, ConfigManager::default().chat_model);
+        assert!(path.is_file());
+
+        fs::write(path, "{ \"chat_model\": ").unwrap();
+        assert!(ConfigManager::load(path).is_err());
+        assert_eq!(fs::read_to_string(path).unwrap(), "{ \"chat_model\": ");
+        fs::remove_file(path).unwrap();
+    }
+
     #[test]
     fn test_read_file_incomplete_structure() {
         let config_data = r#"
@@ -0,0 +1,104 @@
+/// Where a message without a command came from, as far as deciding to answer it goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Addressing<'a> {
+    /// Private chats are always a conversation with the bot
+    pub is_private: bool,
+    /// The message replies to one of the bot's messages
+    pub replies_to_bot: bool,
+    /// Username of the bot, without the `@`
+    pub bot_username: &'a str,
+}
+
+/// The prompt in `text` if the message is meant for the bot, with the mention of the bot
+/// taken out. Group messages are only for the bot when they mention it or reply to it.
+/// The prompt is empty for a bare mention, which only makes sense with a photo.
+#[must_use]
+pub fn addressed_prompt(text: &str, addressing: Addressing) -> Option<String> {
+    // Commands for other bots or unknown commands are not conversation
+    if text.starts_with('/') {
+        return None;
+    }
+
+    let mention = format!("@{}", addressing.bot_username).to_lowercase();
+    let mention_at = (!addressing.bot_username.is_empty())
+        .then(|| text.to_lowercase().find(&mention))
+        .flatten()
+        // Lowercasing can change byte offsets, only trust the match if it lines up
+        .filter(|at| {
+            text.get(*at..*at + mention.len())
+                .is_some_and(|found| found.eq_ignore_ascii_case(&mention))
+        });
+
+    let prompt = match mention_at {
+        Some(at) => {
+            let before = text[..at].trim_end();
+            let after = text[at + mention.len()..].trim_start();
+            if before.is_empty() || after.is_empty() {
+                format!("{before}{after}")
+            } else {
+                format!("{before} {after}")
+            }
+        }
+        None if addressing.is_private || addressing.replies_to_bot => text.to_string(),
+        None => return None,
+    };
+
+    Some(
+        prompt
+            .trim()
+            .trim_start_matches([',', ':'])
+            .trim()
+            .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GROUP: Addressing = Addressing {
+        is_private: false,
+        replies_to_bot: false,
+        bot_username: "gpt_bot",
+    };
+
+    #[test]
+    fn test_private_chats_always_answered() {
+        let private = Addressing {
+            is_private: true,
+            ..GROUP
+        };
+        assert_eq!(
+            addressed_prompt("What is Rust?", private).as_deref(),
+            Some("What is Rust?")
+        );
+        assert_eq!(addressed_prompt("/start", private), None);
+    }
+
+    #[test]
+    fn test_groups_need_mention_or_reply() {
+        assert_eq!(addressed_prompt("What is Rust?", GROUP), None);
+        assert_eq!(
+            addressed_prompt("@GPT_bot, what is Rust?", GROUP).as_deref(),
+            Some("what is Rust?")
+        );
+        assert_eq!(
+            addressed_prompt("Tell me @gpt_bot a joke", GROUP).as_deref(),
+            Some("Tell me a joke")
+        );
+        assert_eq!(addressed_prompt("@gpt_bot", GROUP).as_deref(), Some(""));
+
+        let reply = Addressing {
+            replies_to_bot: true,
+            ..GROUP
+        };
+        assert_eq!(
+            addressed_prompt("And in Go?", reply).as_deref(),
+            Some("And in Go?")
+        );
+
+        // Documents without a caption are only for the bot when they reply to it
+        assert_eq!(addressed_prompt("", GROUP), None);
+        assert_eq!(addressed_prompt("", reply).as_deref(), Some(""));
+    }
+}
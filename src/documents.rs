@@ -0,0 +1,192 @@
+use super::config_manager::DocumentConfig;
+use super::telegram_file::download;
+
+use anyhow::{anyhow, bail, Result};
+
+use serde_derive::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use teloxide::prelude::*;
+use teloxide::types::Document;
+
+/// Text read from a document that was sent to a chat
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DocumentText {
+    /// File name of the document
+    pub name: String,
+    pub text: String,
+    /// The document was longer and only its beginning was kept
+    pub truncated: bool,
+}
+
+impl DocumentText {
+    /// The document as a message of the chat history, cut down to `max_chars`
+    #[must_use]
+    pub fn as_context(&self, max_chars: usize) -> String {
+        let (text, truncated) = truncate_chars(&self.text, max_chars);
+        let note = if truncated || self.truncated {
+            "\n[The rest of the document was left out]"
+        } else {
+            ""
+        };
+        format!("[Document: {}]\n{text}{note}", self.name)
+    }
+}
+
+/// Download a document and read its text, PDFs and any UTF-8 text file can be read
+/// # Errors
+/// Files over the size limit, unreadable files, Telegram API errors
+pub async fn read_document(
+    bot: &Bot,
+    document: &Document,
+    config: &DocumentConfig,
+) -> Result<DocumentText> {
+    let name = document
+        .file_name
+        .clone()
+        .unwrap_or_else(|| "document".to_string());
+    if u64::from(document.file.size) > config.max_file_bytes {
+        bail!(
+            "{name} is larger than the {} KB I can read",
+            config.max_file_bytes / 1000
+        );
+    }
+
+    let data = download(bot, &document.file.id).await?;
+    let is_pdf = document
+        .mime_type
+        .as_ref()
+        .is_some_and(|mime| mime.essence_str() == "application/pdf");
+    // PDF parsing is slow and can panic on broken files, keep it off the async threads
+    let text = tokio::task::spawn_blocking(move || extract_text(&data, is_pdf)).await??;
+
+    let (text, truncated) = truncate_chars(&text, config.max_chars);
+    Ok(DocumentText {
+        name,
+        text,
+        truncated,
+    })
+}
+
+/// Text of a PDF or of a UTF-8 text file
+/// # Errors
+/// Binary files and PDFs that cannot be parsed
+pub fn extract_text(data: &[u8], is_pdf: bool) -> Result<String> {
+    let text = if is_pdf || data.starts_with(b"%PDF") {
+        pdf_extract::extract_text_from_mem(data)
+            .map_err(|error| anyhow!("Failed to read the PDF: {error}"))?
+    } else {
+        match std::str::from_utf8(data) {
+            Ok(text) if !text.contains('\0') => text.to_string(),
+            _ => bail!("Only text files and PDFs can be read"),
+        }
+    };
+
+    let text = text.trim();
+    if text.is_empty() {
+        bail!("The document has no text in it");
+    }
+    Ok(text.to_string())
+}
+
+/// The first `max_chars` characters of `text`, and whether anything was cut off
+#[must_use]
+pub fn truncate_chars(text: &str, max_chars: usize) -> (String, bool) {
+    match text.char_indices().nth(max_chars) {
+        Some((cut, _)) => (text[..cut].to_string(), true),
+        None => (text.to_string(), false),
+    }
+}
+
+/// The last document read in each chat, one JSON file per chat
+pub struct DocumentStore {
+    dir: PathBuf,
+}
+
+impl DocumentStore {
+    #[must_use]
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn path(&self, chat_id: &str) -> PathBuf {
+        self.dir.join(format!("{chat_id}.json"))
+    }
+
+    /// The last document read in a chat, if there was one
+    /// # Errors
+    /// OS file read or deserialization errors
+    pub fn latest(&self, chat_id: &str) -> Result<Option<DocumentText>> {
+        let path = self.path(chat_id);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+    }
+
+    /// Keep `document` as the last document of a chat
+    /// # Errors
+    /// OS file errors
+    pub fn save(&self, chat_id: &str, document: &DocumentText) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(chat_id);
+        let temp_path = path.with_extension(format!("json.{}.tmp", process::id()));
+        fs::write(&temp_path, serde_json::to_string(document)?)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_text() {
+        assert_eq!(
+            extract_text(b"fn main() {}\n", false).unwrap(),
+            "fn main() {}"
+        );
+        assert!(extract_text(&[0xff, 0xfe, 0x00, 0x01], false).is_err());
+        assert!(extract_text(b"text\0with nul", false).is_err());
+        assert!(extract_text(b"  \n", false).is_err());
+        assert!(extract_text(b"%PDF-1.4 broken", false).is_err());
+    }
+
+    #[test]
+    fn test_context_is_truncated() {
+        let document = DocumentText {
+            name: "notes.md".to_string(),
+            text: "äbcdef".to_string(),
+            truncated: false,
+        };
+        assert_eq!(document.as_context(10), "[Document: notes.md]\näbcdef");
+        assert_eq!(
+            document.as_context(3),
+            "[Document: notes.md]\näbc\n[The rest of the document was left out]"
+        );
+    }
+
+    #[test]
+    fn test_store_keeps_latest() {
+        let dir = Path::new("test-chat-documents");
+        let store = DocumentStore::new(dir);
+        assert_eq!(store.latest("1").unwrap(), None);
+
+        for name in ["first.txt", "second.txt"] {
+            let document = DocumentText {
+                name: name.to_string(),
+                text: "text".to_string(),
+                truncated: false,
+            };
+            store.save("1", &document).unwrap();
+        }
+        assert_eq!(store.latest("1").unwrap().unwrap().name, "second.txt");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use super::chat_history::{ChatHistory, MessageChat};
+use super::config_manager::{ConfigManager, HistoryBackend};
+
+use anyhow::Result;
+
+use rusqlite::{params, Connection};
+
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Persistent storage for the chat histories, keyed by chat ID
+pub trait HistoryStore: Send {
+    /// Load the history of a chat, `None` if nothing was stored for it yet
+    /// # Errors
+    /// Storage read or deserialization errors
+    fn load(&self, chat_id: &str) -> Result<Option<ChatHistory>>;
+
+    /// Replace the whole history of a chat
+    /// # Errors
+    /// Storage write errors
+    fn save(&self, chat_id: &str, history: &ChatHistory) -> Result<()>;
+
+    /// Add a single message to the end of the history of a chat
+    /// # Errors
+    /// Storage write errors
+    fn append(&self, chat_id: &str, message: &MessageChat) -> Result<()>;
+
+    /// Remove the history of a chat, nothing happens if there is none
+    /// # Errors
+    /// Storage write errors
+    fn delete(&self, chat_id: &str) -> Result<()>;
+
+    /// Chat IDs of the stored histories that start with `prefix`
+    /// # Errors
+    /// Storage read errors
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+}
+
+/// Open the store selected in the config file
+/// # Errors
+/// Config file errors or failure to open the store
+pub fn open_store() -> Result<Box<dyn HistoryStore>> {
+    let config = ConfigManager::new()?;
+    Ok(match config.history.backend {
+        HistoryBackend::Json => Box::new(JsonFileStore::new(&config.history.json_dir)),
+        HistoryBackend::Sqlite => {
+            Box::new(SqliteStore::open(Path::new(&config.history.sqlite_path))?)
+        }
+    })
+}
+
+/// One pretty printed JSON file per chat, rewritten on every change
+pub struct JsonFileStore {
+    dir: PathBuf,
+}
+
+impl JsonFileStore {
+    #[must_use]
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    fn path(&self, chat_id: &str) -> PathBuf {
+        self.dir.join(format!("{chat_id}-history.json"))
+    }
+}
+
+impl HistoryStore for JsonFileStore {
+    fn load(&self, chat_id: &str) -> Result<Option<ChatHistory>> {
+        let path = self.path(chat_id);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let mut file = File::open(path)?;
+
+        let mut json_string = String::new();
+        file.read_to_string(&mut json_string)?;
+
+        let serialized_data: ChatHistory = serde_json::from_str(&json_string)?;
+
+        Ok(Some(serialized_data))
+    }
+
+    fn save(&self, chat_id: &str, history: &ChatHistory) -> Result<()> {
+        // If the chat history directory does not exist make it
+        create_dir_all(&self.dir)?;
+
+        let json_string = serde_json::to_string_pretty(history)?;
+
+        // Write next to the real file and swap it in, readers never see a half written file
+        let path = self.path(chat_id);
+        let temp_path = path.with_extension(format!("json.{}.tmp", process::id()));
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(false)
+            .truncate(true)
+            .open(&temp_path)?;
+
+        file.write_all(json_string.as_bytes())?;
+        file.sync_all()?;
+
+        rename(temp_path, path)?;
+
+        Ok(())
+    }
+
+    fn append(&self, chat_id: &str, message: &MessageChat) -> Result<()> {
+        let mut history = self
+            .load(chat_id)?
+            .unwrap_or_else(|| ChatHistory { messages: vec![] });
+        history.messages.push(message.clone());
+        self.save(chat_id, &history)
+    }
+
+    fn delete(&self, chat_id: &str) -> Result<()> {
+        let path = self.path(chat_id);
+        if path.is_file() {
+            remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        if !self.dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut chat_ids = Vec::new();
+        for entry in read_dir(&self.dir)? {
+            let file_name = entry?.file_name();
+            let chat_id = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix("-history.json"))
+                .filter(|chat_id| chat_id.starts_with(prefix));
+            if let Some(chat_id) = chat_id {
+                chat_ids.push(chat_id.to_string());
+            }
+        }
+        Ok(chat_ids)
+    }
+}
+
+/// All chats in a single SQLite database, one row per message
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    /// Open or create the database and its schema
+    /// # Errors
+    /// SQLite errors
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                chat_id TEXT NOT NULL,
+                role TEXT NOT NULL,
+                content TEXT NOT NULL,
+                message TEXT NOT NULL,
+                created_at INTEGER NOT NULL DEFAULT (unixepoch())
+            );
+            CREATE INDEX IF NOT EXISTS messages_chat_id ON messages (chat_id, id);",
+        )?;
+        Ok(Self { connection })
+    }
+
+    fn insert(connection: &Connection, chat_id: &str, message: &MessageChat) -> Result<()> {
+        // Role and content get their own columns for querying, the full message is kept as JSON
+        connection.execute(
+            "INSERT INTO messages (chat_id, role, content, message) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                chat_id,
+                message.role,
+                message.content,
+                serde_json::to_string(message)?
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+impl HistoryStore for SqliteStore {
+    fn load(&self, chat_id: &str) -> Result<Option<ChatHistory>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT message FROM messages WHERE chat_id = ?1 ORDER BY id")?;
+
+        let messages = statement
+            .query_map(params![chat_id], |row| row.get::<_, String>(0))?
+            .map(|json| Ok(serde_json::from_str::<MessageChat>(&json?)?))
+            .collect::<Result<Vec<_>>>()?;
+
+        if messages.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(ChatHistory { messages }))
+    }
+
+    fn save(&self, chat_id: &str, history: &ChatHistory) -> Result<()> {
+        let transaction = self.connection.unchecked_transaction()?;
+        transaction.execute("DELETE FROM messages WHERE chat_id = ?1", params![chat_id])?;
+        for message in &history.messages {
+            Self::insert(&transaction, chat_id, message)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn append(&self, chat_id: &str, message: &MessageChat) -> Result<()> {
+        Self::insert(&self.connection, chat_id, message)
+    }
+
+    fn delete(&self, chat_id: &str) -> Result<()> {
+        self.connection
+            .execute("DELETE FROM messages WHERE chat_id = ?1", params![chat_id])?;
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        // Compared as text, LIKE would treat `_` and `%` in chat IDs as wildcards
+        let mut statement = self.connection.prepare(
+            "SELECT DISTINCT chat_id FROM messages WHERE substr(chat_id, 1, length(?1)) = ?1",
+        )?;
+        let chat_ids = statement
+            .query_map(params![prefix], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(chat_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn round_trip(store: &dyn HistoryStore) {
+        assert!(store.load("store_test").unwrap().is_none());
+
+        let history = ChatHistory {
+            messages: vec![MessageChat::new("system", "Base prompt")],
+        };
+        store.save("store_test", &history).unwrap();
+        store
+            .append("store_test", &MessageChat::new("user", "Hello"))
+            .unwrap();
+        store
+            .append("store_test_other", &MessageChat::new("user", "Other chat"))
+            .unwrap();
+
+        let loaded = store.load("store_test").unwrap().unwrap();
+        assert_eq!(loaded.messages.len(), 2);
+        assert_eq!(loaded.messages[1].content, "Hello");
+
+        // Saving replaces instead of adding
+        store.save("store_test", &history).unwrap();
+        let loaded = store.load("store_test").unwrap().unwrap();
+        assert_eq!(loaded.messages.len(), 1);
+        assert_eq!(loaded.messages[0].role, "system");
+
+        assert_eq!(
+            store.list("store_test_o").unwrap(),
+            vec!["store_test_other"]
+        );
+
+        store.delete("store_test").unwrap();
+        assert!(store.load("store_test").unwrap().is_none());
+        assert!(store.load("store_test_other").unwrap().is_some());
+        store.delete("store_test").unwrap();
+    }
+
+    #[test]
+    fn test_json_file_store() {
+        let dir = "test-history-store-json";
+        round_trip(&JsonFileStore::new(dir));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        let path = Path::new("test-history-store.db");
+        round_trip(&SqliteStore::open(path).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+}
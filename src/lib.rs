@@ -1,4 +1,25 @@
+pub mod access;
+pub mod anthropic_api;
+pub mod api_error;
 pub mod chat_history;
+pub mod chat_settings;
 pub mod config_manager;
+pub mod conversation;
+pub mod documents;
+pub mod history_store;
+pub mod message_split;
+pub mod named_conversations;
+pub mod ollama_api;
 pub mod open_ai_api;
+pub mod personas;
+pub mod provider;
+pub mod quota;
+pub mod rate_limit;
 pub mod response;
+pub mod retry;
+pub mod telegram_file;
+pub mod telegram_format;
+pub mod threads;
+pub mod tools;
+pub mod usage;
+pub mod vision;
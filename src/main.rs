@@ -1,5 +1,5 @@
 use std::env;
-use teloxide::{prelude::*, utils::command::BotCommands};
+use teloxide::{prelude::*, types::Me, utils::command::BotCommands};
 use tg_gpt_bot::response;
 
 #[tokio::main]
@@ -16,7 +16,21 @@ async fn main() {
 
     let bot = Bot::from_env();
 
-    Command::repl(bot, answer).await;
+    // Commands first, every other message may be conversation with the bot
+    let messages = Update::filter_message()
+        .branch(dptree::entry().filter_command::<Command>().endpoint(answer))
+        .branch(dptree::endpoint(converse));
+    let handler = dptree::entry()
+        .branch(messages)
+        .branch(Update::filter_edited_message().endpoint(edited))
+        .branch(Update::filter_callback_query().endpoint(press));
+
+    Dispatcher::builder(bot, handler)
+        .default_handler(|_update| async {})
+        .enable_ctrlc_handler()
+        .build()
+        .dispatch()
+        .await;
 
     println!("Bot closed...");
 }
@@ -41,10 +55,69 @@ enum Command {
     Image(String),
     #[command(description = "Play some skill games")]
     Gamble(String),
+    #[command(description = "Show the API usage of this chat and yourself")]
+    Usage,
+    #[command(description = "Admins only: lift quotas with 'chat' or a user ID, then hours")]
+    Grant(String),
+    #[command(description = "Answer messages without /chat: 'on' or 'off'")]
+    AutoReply(String),
+    #[command(description = "Answer a prompt with a voice message")]
+    Say(String),
+    #[command(description = "Also answer chat messages with voice messages: 'on' or 'off'")]
+    VoiceReplies(String),
+    #[command(description = "Summarize the document you reply to, or the last one sent")]
+    Summarize,
+    #[command(description = "Forget the last question and answer")]
+    Undo,
+    #[command(description = "Answer the last question again")]
+    Retry,
+    #[command(description = "Start a named conversation, optionally with a system prompt")]
+    New(String),
+    #[command(description = "Continue another named conversation, 'main' is the first one")]
+    Switch(String),
+    #[command(description = "List the named conversations of this chat")]
+    List,
+    #[command(description = "Delete a named conversation and its history")]
+    Delete(String),
+    #[command(
+        description = "Start over with a saved system prompt: a name, list, show, save or delete"
+    )]
+    Persona(String),
+}
+
+impl Command {
+    /// Name of the command as it is typed, used to look up its permission level
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Help => "help",
+            Command::Source => "source",
+            Command::TestApi => "testapi",
+            Command::Chat(_) => "chat",
+            Command::ChatPurge(_) => "chatpurge",
+            Command::Image(_) => "image",
+            Command::Gamble(_) => "gamble",
+            Command::Usage => "usage",
+            Command::Grant(_) => "grant",
+            Command::AutoReply(_) => "autoreply",
+            Command::Say(_) => "say",
+            Command::VoiceReplies(_) => "voicereplies",
+            Command::Summarize => "summarize",
+            Command::Undo => "undo",
+            Command::Retry => "retry",
+            Command::New(_) => "new",
+            Command::Switch(_) => "switch",
+            Command::List => "list",
+            Command::Delete(_) => "delete",
+            Command::Persona(_) => "persona",
+        }
+    }
 }
 
 async fn answer(bot: Bot, msg: Message, cmd: Command) -> ResponseResult<()> {
     let responder = response::Response { bot, msg };
+    if !responder.authorize(cmd.name()).await? || !responder.throttle(cmd.name()).await? {
+        return Ok(());
+    }
     match cmd {
         Command::Help => {
             responder.help(Command::descriptions().to_string()).await?;
@@ -67,6 +140,71 @@ async fn answer(bot: Bot, msg: Message, cmd: Command) -> ResponseResult<()> {
         Command::Gamble(prompt) => {
             responder.gamble(prompt).await?;
         }
+        Command::Usage => {
+            responder.usage().await?;
+        }
+        Command::Grant(prompt) => {
+            responder.grant(prompt).await?;
+        }
+        Command::AutoReply(prompt) => {
+            responder.auto_reply(prompt).await?;
+        }
+        Command::Say(prompt) => {
+            responder.say(prompt).await?;
+        }
+        Command::VoiceReplies(prompt) => {
+            responder.voice_replies(prompt).await?;
+        }
+        Command::Summarize => {
+            responder.summarize().await?;
+        }
+        Command::Undo => {
+            responder.undo().await?;
+        }
+        Command::Retry => {
+            responder.retry().await?;
+        }
+        Command::New(prompt) => {
+            responder.new_conversation(prompt).await?;
+        }
+        Command::Switch(prompt) => {
+            responder.switch_conversation(prompt).await?;
+        }
+        Command::List => {
+            responder.list_conversations().await?;
+        }
+        Command::Delete(prompt) => {
+            responder.delete_conversation(prompt).await?;
+        }
+        Command::Persona(prompt) => {
+            responder.persona(prompt).await?;
+        }
     };
     Ok(())
 }
+
+async fn converse(bot: Bot, msg: Message, me: Me) -> ResponseResult<()> {
+    let responder = response::Response { bot, msg };
+    responder.converse(&me).await
+}
+
+async fn press(bot: Bot, query: CallbackQuery) -> ResponseResult<()> {
+    // Buttons are only on answers, which are regular messages
+    let Some(msg) = query.regular_message().cloned() else {
+        bot.answer_callback_query(query.id).await?;
+        return Ok(());
+    };
+    let responder = response::Response { bot, msg };
+    responder.answer_action(&query).await
+}
+
+async fn edited(bot: Bot, msg: Message, me: Me) -> ResponseResult<()> {
+    // Edits of /chat are answered again, other commands are not
+    let command_prompt = match msg.text().map(|text| Command::parse(text, me.username())) {
+        Some(Ok(Command::Chat(prompt))) => Some(prompt),
+        Some(Ok(_)) => return Ok(()),
+        _ => None,
+    };
+    let responder = response::Response { bot, msg };
+    responder.edited(command_prompt, &me).await
+}
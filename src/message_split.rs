@@ -0,0 +1,180 @@
+/// Longest text Telegram accepts in one message, counted in UTF-16 code units
+pub const TELEGRAM_MESSAGE_LIMIT: usize = 4096;
+
+const FENCE: &str = "```";
+
+/// Length of `text` the way Telegram counts it
+fn units(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Split `text` into parts of at most `limit` UTF-16 code units.
+/// Parts end on paragraph or line boundaries where possible, and a code block that has to be
+/// split is closed at the end of one part and opened again with the same fence at the start
+/// of the next one, so every part renders on its own.
+#[must_use]
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut splitter = Splitter {
+        limit,
+        parts: Vec::new(),
+        lines: Vec::new(),
+        len: 0,
+        fence: None,
+        last_break: None,
+    };
+    for line in text.split_inclusive('\n') {
+        splitter.push(line);
+    }
+    splitter.flush(splitter.lines.len());
+    splitter.parts
+}
+
+struct Splitter {
+    limit: usize,
+    parts: Vec<String>,
+    /// Lines of the part being built, each with its line break
+    lines: Vec<String>,
+    len: usize,
+    /// Opening line of the code block that is open at the end of `lines`
+    fence: Option<String>,
+    /// Index of the last blank line in `lines` that is outside of a code block
+    last_break: Option<usize>,
+}
+
+impl Splitter {
+    fn push(&mut self, line: &str) {
+        let is_fence = line.trim_start().starts_with(FENCE);
+        let fence_after = match (&self.fence, is_fence) {
+            (Some(_), true) => None,
+            (None, true) => Some(line.trim_end().to_string()),
+            (fence, false) => fence.clone(),
+        };
+        let closing = fence_after.as_ref().map_or(0, |_| FENCE.len() + 1);
+
+        if !self.lines.is_empty() && self.len + units(line) + closing > self.limit {
+            self.flush_at_break();
+        }
+
+        // A single line that does not fit is cut wherever it has to be
+        let reopen = self.fence.as_ref().map_or(0, |fence| units(fence) + 1);
+        let room = self.limit.saturating_sub(reopen + closing).max(1);
+        if units(line) > room {
+            let mut chunk = String::new();
+            let mut chunk_len = 0;
+            for c in line.chars() {
+                if chunk_len + c.len_utf16() > room {
+                    self.append(&chunk, self.fence.clone());
+                    self.flush(self.lines.len());
+                    chunk.clear();
+                    chunk_len = 0;
+                }
+                chunk.push(c);
+                chunk_len += c.len_utf16();
+            }
+            self.append(&chunk, fence_after);
+            return;
+        }
+
+        self.append(line, fence_after);
+    }
+
+    fn append(&mut self, line: &str, fence_after: Option<String>) {
+        if self.fence.is_none() && fence_after.is_none() && line.trim().is_empty() {
+            self.last_break = Some(self.lines.len());
+        }
+        self.len += units(line);
+        self.lines.push(line.to_string());
+        self.fence = fence_after;
+    }
+
+    /// Flush up to the last paragraph break, if that does not leave the part too short
+    fn flush_at_break(&mut self) {
+        let at = match self.last_break {
+            Some(index) if units(&self.lines[..=index].concat()) * 2 >= self.limit => index + 1,
+            _ => self.lines.len(),
+        };
+        self.flush(at);
+    }
+
+    /// Finish a part with the first `at` lines and start the next one with the rest
+    fn flush(&mut self, at: usize) {
+        let rest = self.lines.split_off(at);
+        let mut part = self.lines.concat().trim_matches('\n').to_string();
+
+        // The rest is only split off at a paragraph break, which is never inside a code block
+        let open_fence = if rest.is_empty() {
+            self.fence.take()
+        } else {
+            None
+        };
+        if open_fence.is_some() {
+            part.push('\n');
+            part.push_str(FENCE);
+        }
+        if !part.trim().is_empty() {
+            self.parts.push(part);
+        }
+
+        self.lines.clear();
+        self.len = 0;
+        self.last_break = None;
+        self.fence = None;
+        if let Some(opening) = open_fence {
+            self.append(&format!("{opening}\n"), Some(opening.clone()));
+        }
+        for line in rest {
+            self.push(&line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_text_is_one_part() {
+        assert_eq!(split_message("Hello there", 20), vec!["Hello there"]);
+        assert!(split_message("", 20).is_empty());
+    }
+
+    #[test]
+    fn test_split_on_paragraphs_and_lines() {
+        let text = "First paragraph line\n\nSecond paragraph\nstill second\n";
+        let parts = split_message(text, 40);
+        assert_eq!(
+            parts,
+            vec!["First paragraph line", "Second paragraph\nstill second"]
+        );
+        for part in split_message(&"word ".repeat(100), 30) {
+            assert!(units(&part) <= 30);
+        }
+    }
+
+    #[test]
+    fn test_code_blocks_are_reopened() {
+        let code: String = (0..10).map(|i| format!("let x{i} = {i};\n")).collect();
+        let text = format!("Here is code:\n```rust\n{code}```\nDone.");
+        let parts = split_message(&text, 80);
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(units(part) <= 80, "{part}");
+            // Every part has balanced fences
+            assert_eq!(part.matches(FENCE).count() % 2, 0, "{part}");
+        }
+        assert!(parts[1].starts_with("```rust\n"));
+        assert!(parts.last().unwrap().ends_with("Done."));
+        let joined: String = parts.concat();
+        for i in 0..10 {
+            assert!(joined.contains(&format!("let x{i} = {i};")));
+        }
+    }
+
+    #[test]
+    fn test_long_line_is_cut() {
+        let text = "é".repeat(25);
+        let parts = split_message(&text, 10);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts.concat(), text);
+    }
+}
@@ -0,0 +1,228 @@
+use super::chat_history::ChatHistory;
+use super::chat_settings::ChatSettingsStore;
+use super::threads::{drop_branches, ThreadIndex};
+
+use anyhow::{bail, Result};
+
+/// Name of the conversation every chat starts in, it cannot be deleted
+pub const MAIN: &str = "main";
+
+const MAX_NAME_CHARS: usize = 32;
+
+/// Key of the history of conversation `name` in a chat, the main one keeps the chat ID
+#[must_use]
+pub fn history_key(chat_id: &str, name: Option<&str>) -> String {
+    match name {
+        Some(name) if name != MAIN => format!("{chat_id}-c-{name}"),
+        _ => chat_id.to_string(),
+    }
+}
+
+/// Key of the history that new messages of a chat continue
+/// # Errors
+/// Chat settings errors
+pub fn active_key(store: &ChatSettingsStore, chat_id: &str) -> Result<String> {
+    let settings = store.get(chat_id)?;
+    Ok(history_key(
+        chat_id,
+        settings.active_conversation.as_deref(),
+    ))
+}
+
+/// The conversation or persona name typed in a command, lowercased.
+/// Names end up in history keys and file names, so only letters, digits, `-` and `_` pass.
+/// # Errors
+/// The name is empty, too long or has other characters
+pub fn parse_name(argument: &str) -> Result<String> {
+    let name = argument.trim().to_lowercase();
+    if name.is_empty() {
+        bail!("A name is needed");
+    }
+    if name.chars().count() > MAX_NAME_CHARS
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Names are up to {MAX_NAME_CHARS} letters, digits, '-' or '_'");
+    }
+    Ok(name)
+}
+
+/// Start conversation `name` with its own system prompt, the base prompt if `prompt` is
+/// empty, and make it the active one
+/// # Errors
+/// The name is taken, the chat has `max` conversations already, settings or history errors
+pub fn create(
+    store: &ChatSettingsStore,
+    chat_id: &str,
+    name: &str,
+    prompt: &str,
+    max: usize,
+) -> Result<()> {
+    if name == MAIN {
+        bail!("The main conversation always exists, use /switch {MAIN}");
+    }
+
+    // Checked while the settings are locked, so two /new commands cannot both pass
+    let mut outcome = Ok(());
+    store.update(chat_id, |settings| {
+        if settings
+            .conversations
+            .iter()
+            .any(|existing| existing == name)
+        {
+            outcome = Err(format!(
+                "There is a conversation named '{name}' already, use /switch {name}"
+            ));
+        } else if settings.conversations.len() >= max {
+            outcome = Err(format!(
+                "This chat has {max} conversations already, /delete one first"
+            ));
+        } else {
+            settings.conversations.push(name.to_string());
+            settings.active_conversation = Some(name.to_string());
+        }
+    })?;
+    if let Err(reason) = outcome {
+        bail!(reason);
+    }
+
+    let key = history_key(chat_id, Some(name));
+    ChatHistory::new(&key)?.purge(&key, prompt)?;
+    Ok(())
+}
+
+/// Make conversation `name` the one new messages continue
+/// # Errors
+/// There is no such conversation or settings errors
+pub fn switch(store: &ChatSettingsStore, chat_id: &str, name: &str) -> Result<()> {
+    let mut found = true;
+    store.update(chat_id, |settings| {
+        if name == MAIN {
+            settings.active_conversation = None;
+        } else if settings
+            .conversations
+            .iter()
+            .any(|existing| existing == name)
+        {
+            settings.active_conversation = Some(name.to_string());
+        } else {
+            found = false;
+        }
+    })?;
+    if !found {
+        bail!("There is no conversation named '{name}', see /list");
+    }
+    Ok(())
+}
+
+/// Remove conversation `name` with its history and reply branches, replies to its answers
+/// continue the active conversation afterwards. Deleting the active conversation switches
+/// back to the main one, which is returned as `true`.
+/// # Errors
+/// The name is the main or an unknown conversation, settings, index or history errors
+pub fn delete(
+    store: &ChatSettingsStore,
+    index: &ThreadIndex,
+    chat_id: &str,
+    name: &str,
+) -> Result<bool> {
+    if name == MAIN {
+        bail!("The main conversation cannot be deleted, use /chatpurge to clear it");
+    }
+
+    let mut found = false;
+    let mut was_active = false;
+    store.update(chat_id, |settings| {
+        let before = settings.conversations.len();
+        settings.conversations.retain(|existing| existing != name);
+        found = settings.conversations.len() < before;
+        if settings.active_conversation.as_deref() == Some(name) {
+            settings.active_conversation = None;
+            was_active = true;
+        }
+    })?;
+    if !found {
+        bail!("There is no conversation named '{name}', see /list");
+    }
+
+    let key = history_key(chat_id, Some(name));
+    drop_branches(index, chat_id, &key)?;
+    ChatHistory::delete(&key)?;
+    Ok(was_active)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::threads::{branch_key, ThreadEntry};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(parse_name(" Work_2 ").unwrap(), "work_2");
+        assert_eq!(parse_name("trip-plans").unwrap(), "trip-plans");
+        assert!(parse_name("").is_err());
+        assert!(parse_name("../etc").is_err());
+        assert!(parse_name("two words").is_err());
+        assert!(parse_name(&"a".repeat(MAX_NAME_CHARS + 1)).is_err());
+    }
+
+    #[test]
+    fn test_create_switch_delete() {
+        let path = Path::new("test-named-conversations.json");
+        let index_path = Path::new("test-named-threads.json");
+        let store = ChatSettingsStore::new(path);
+        let index = ThreadIndex::new(index_path);
+        let chat_id = "test-named";
+        assert_eq!(active_key(&store, chat_id).unwrap(), chat_id);
+
+        create(&store, chat_id, "work", "Only talk about work", 2).unwrap();
+        let work = format!("{chat_id}-c-work");
+        assert_eq!(active_key(&store, chat_id).unwrap(), work);
+        assert_eq!(
+            ChatHistory::new(&work).unwrap().messages[0].content,
+            "Only talk about work"
+        );
+
+        // Taken names, the main name and more than the maximum are refused
+        assert!(create(&store, chat_id, "work", "", 2).is_err());
+        assert!(create(&store, chat_id, MAIN, "", 2).is_err());
+        create(&store, chat_id, "fun", "", 2).unwrap();
+        assert!(create(&store, chat_id, "more", "", 2).is_err());
+
+        switch(&store, chat_id, MAIN).unwrap();
+        assert_eq!(active_key(&store, chat_id).unwrap(), chat_id);
+        switch(&store, chat_id, "work").unwrap();
+        assert!(switch(&store, chat_id, "missing").is_err());
+        assert_eq!(active_key(&store, chat_id).unwrap(), work);
+
+        // An answer of the conversation and a reply branch started from it
+        let entry = ThreadEntry {
+            history_key: work.clone(),
+            anchor: 3,
+            asker: Some(1),
+        };
+        index.remember(chat_id, &[3], &entry).unwrap();
+        let branch = branch_key(&work, 4);
+        ChatHistory::new(&branch)
+            .unwrap()
+            .purge(&branch, "Only talk about work")
+            .unwrap();
+
+        // Deleting the active conversation goes back to the main one
+        assert!(!delete(&store, &index, chat_id, "fun").unwrap());
+        assert!(delete(&store, &index, chat_id, "work").unwrap());
+        assert!(delete(&store, &index, chat_id, "work").is_err());
+        assert!(delete(&store, &index, chat_id, MAIN).is_err());
+        assert_eq!(active_key(&store, chat_id).unwrap(), chat_id);
+        assert!(ChatHistory::find(&work).unwrap().is_none());
+        assert!(ChatHistory::find(&branch).unwrap().is_none());
+        assert!(index.lookup(chat_id, 3).unwrap().is_none());
+        assert!(store.get(chat_id).unwrap().conversations.is_empty());
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(index_path).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+use super::api_error::{check_status, ApiError};
+use super::chat_history::MessageChat;
+use super::config_manager::{ConfigManager, RetryConfig};
+use super::provider::{without_tool_turns, ChatCompletion, ChatProvider, ChatRequest, LineDecoder};
+use super::retry::send_with_retry;
+use super::usage::TokenUsage;
+use super::vision::encode_image;
+
+use log::trace;
+use tokio::sync::watch;
+
+use anyhow::{anyhow, Result};
+
+use async_trait::async_trait;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Client for a self-hosted Ollama style server
+pub struct OllamaApi {
+    uri: String,
+    retry: RetryConfig,
+}
+
+impl OllamaApi {
+    /// Form an Ollama interface from the config file, does not make any requests by itself
+    /// # Errors
+    /// Config file errors
+    pub fn new() -> Result<Self> {
+        let config = ConfigManager::new()?;
+        Ok(Self {
+            uri: config.providers.ollama_uri,
+            retry: config.retry,
+        })
+    }
+
+    async fn ollama_post(&self, request: &ChatRequest, stream: bool) -> Result<reqwest::Response> {
+        let request_data = RequestOllama {
+            model: request.model.clone(),
+            messages: without_tool_turns(&request.messages)
+                .iter()
+                .map(MessageOllama::from)
+                .collect(),
+            stream,
+            options: OptionsOllama {
+                num_predict: request.max_tokens,
+            },
+        };
+
+        let body = serde_json::to_string(&request_data)?;
+        trace!("Ollama request body: {body}");
+
+        let client = reqwest::Client::new();
+        let request = client
+            .post(format!("{}/api/chat", self.uri))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        let response = send_with_retry(request, &self.retry).await?;
+
+        check_status(response).await
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OllamaApi {
+    async fn complete(&self, request: &ChatRequest) -> Result<ChatCompletion> {
+        let response = self.ollama_post(request, false).await?.text().await?;
+        trace!("Ollama response: {response}");
+        let json: ResponseOllama = serde_json::from_str(&response)?;
+
+        let usage = json.usage();
+        match json.message {
+            Some(message) if !message.content.is_empty() => Ok(ChatCompletion {
+                content: message.content,
+                usage,
+                tool_calls: Vec::new(),
+            }),
+            _ => Err(anyhow!("No output found.")),
+        }
+    }
+
+    async fn complete_stream(
+        &self,
+        request: &ChatRequest,
+        progress: &watch::Sender<String>,
+    ) -> Result<ChatCompletion> {
+        let mut response = self.ollama_post(request, true).await?;
+
+        // Streamed answers are sent as one JSON object per line
+        let mut decoder = LineDecoder::default();
+        let mut output = String::new();
+        let mut usage = None;
+        'stream: while let Some(chunk) = response.chunk().await.map_err(ApiError::from)? {
+            for line in decoder.push(&chunk) {
+                if line.is_empty() {
+                    continue;
+                }
+                trace!("Ollama stream chunk: {line}");
+                let json: ResponseOllama = serde_json::from_str(&line)?;
+                usage = json.usage().or(usage);
+                if let Some(message) = json.message.filter(|m| !m.content.is_empty()) {
+                    output.push_str(&message.content);
+                    progress.send_replace(output.clone());
+                }
+                if json.done {
+                    break 'stream;
+                }
+            }
+        }
+
+        if output.is_empty() {
+            return Err(anyhow!("No output found."));
+        }
+
+        Ok(ChatCompletion {
+            content: output,
+            usage,
+            tool_calls: Vec::new(),
+        })
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct RequestOllama {
+    model: String,
+    messages: Vec<MessageOllama>,
+    stream: bool,
+    options: OptionsOllama,
+}
+
+#[derive(Serialize, Debug)]
+struct MessageOllama {
+    role: String,
+    content: String,
+    /// Base64 encoded pictures
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+impl From<&MessageChat> for MessageOllama {
+    fn from(message: &MessageChat) -> Self {
+        MessageOllama {
+            role: message.role.clone(),
+            content: message.content.clone(),
+            images: message.images.iter().filter_map(encode_image).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct OptionsOllama {
+    num_predict: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct ResponseOllama {
+    message: Option<MessageChat>,
+    #[serde(default)]
+    done: bool,
+    // Only sent with the final message
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+}
+
+impl ResponseOllama {
+    fn usage(&self) -> Option<TokenUsage> {
+        Some(TokenUsage {
+            prompt_tokens: self.prompt_eval_count?,
+            completion_tokens: self.eval_count.unwrap_or_default(),
+        })
+    }
+}
@@ -1,16 +1,45 @@
-use super::chat_history::{ChatHistory, MessageChat, Role};
+use super::api_error::{check_status, ApiError};
+use super::chat_history::{
+    estimate_tokens, ChatHistory, Exchange, ImageRef, MessageChat, Role, ToolCall,
+};
 use super::config_manager::ConfigManager;
+use super::documents::DocumentText;
+use super::message_split::split_message;
+use super::provider::{
+    chat_provider, image_provider, ChatCompletion, ChatProvider, ChatRequest, ImageProvider,
+    ImageRequest, LineDecoder,
+};
+use super::quota;
+use super::retry::send_with_retry;
+use super::tools::{ToolRegistry, ToolSpec};
+use super::usage::{Caller, TokenUsage, UsageLedger, UsageRecord};
+use super::vision::{encode_image, keep_recent_images};
 
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use reqwest::multipart::{Form, Part};
+use std::collections::HashSet;
 use std::env;
+use tokio::sync::watch;
 
 use anyhow::{anyhow, Result};
 
+use async_trait::async_trait;
+
 use serde_derive::{Deserialize, Serialize};
 
+/// The text of a chat answer and the history entries of its exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatAnswer {
+    pub text: String,
+    /// `None` when nothing was asked, such as for an empty prompt
+    pub exchange: Option<Exchange>,
+}
+
 pub struct OpenAiApi {
     uri: String,
     auth_header: String,
+    caller: Option<Caller>,
+    images: Vec<ImageRef>,
 }
 
 // Default is to use the constructor always
@@ -35,20 +64,102 @@ impl OpenAiApi {
 
         let auth_header: String = format!("Bearer {token}");
 
-        Self { uri, auth_header }
+        Self {
+            uri,
+            auth_header,
+            caller: None,
+            images: Vec::new(),
+        }
+    }
+
+    /// Account the usage of the requests made through this interface to `caller`
+    #[must_use]
+    pub fn with_caller(mut self, caller: Caller) -> Self {
+        self.caller = Some(caller);
+        self
+    }
+
+    /// Attach pictures to the next chat prompt sent through this interface
+    #[must_use]
+    pub fn with_images(mut self, images: Vec<ImageRef>) -> Self {
+        self.images = images;
+        self
+    }
+
+    fn caller(&self, chat_id: &str) -> Caller {
+        self.caller.clone().unwrap_or_else(|| Caller {
+            chat_id: chat_id.to_string(),
+            user_id: None,
+        })
+    }
+
+    /// Record a completion in the usage ledger, estimating the tokens if the backend did not
+    /// report them. Failing to record is logged and does not fail the request.
+    fn record_chat_usage(&self, chat_id: &str, request: &ChatRequest, completion: &ChatCompletion) {
+        let usage = completion.usage.unwrap_or_else(|| TokenUsage {
+            prompt_tokens: request
+                .messages
+                .iter()
+                .map(|m| m.estimated_tokens() as u64)
+                .sum(),
+            completion_tokens: estimate_tokens(&completion.content) as u64,
+        });
+        let result = ConfigManager::new().and_then(|config| {
+            let record = UsageRecord::chat(
+                &self.caller(chat_id),
+                &request.model,
+                usage,
+                &config.usage.prices,
+            );
+            UsageLedger::open()?.record(&record)
+        });
+        if let Err(error) = result {
+            warn!("Failed to record usage: {error}");
+        }
+    }
+
+    fn record_audio_usage(&self, record: impl FnOnce(&ConfigManager) -> UsageRecord) {
+        let result =
+            ConfigManager::new().and_then(|config| UsageLedger::open()?.record(&record(&config)));
+        if let Err(error) = result {
+            warn!("Failed to record usage: {error}");
+        }
+    }
+
+    fn record_image_usage(&self, request: &ImageRequest) {
+        let result = ConfigManager::new().and_then(|config| {
+            let record =
+                UsageRecord::images(&self.caller(""), &request.model, 1, &config.usage.prices);
+            UsageLedger::open()?.record(&record)
+        });
+        if let Err(error) = result {
+            warn!("Failed to record usage: {error}");
+        }
     }
 
     async fn openai_post(&self, endpoint: &str, body: &str) -> Result<String> {
+        let config = ConfigManager::new()?;
         let client = reqwest::Client::new();
-        Ok(client
+        let request = client
             .post(format!("{}/{endpoint}", self.uri))
             .header(reqwest::header::CONTENT_TYPE, "application/json")
             .header(reqwest::header::AUTHORIZATION, &self.auth_header)
-            .body(body.to_string())
-            .send()
-            .await?
-            .text()
-            .await?)
+            .body(body.to_string());
+        let response = check_status(send_with_retry(request, &config.retry).await?).await?;
+        Ok(response.text().await.map_err(ApiError::from)?)
+    }
+
+    /// Like `openai_post` for endpoints that answer with a file instead of JSON
+    async fn openai_post_bytes(&self, endpoint: &str, body: &str) -> Result<Vec<u8>> {
+        let config = ConfigManager::new()?;
+        let client = reqwest::Client::new();
+        let request = client
+            .post(format!("{}/{endpoint}", self.uri))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(reqwest::header::AUTHORIZATION, &self.auth_header)
+            .body(body.to_string());
+        let response = check_status(send_with_retry(request, &config.retry).await?).await?;
+        Ok(response.bytes().await.map_err(ApiError::from)?.to_vec())
     }
 
     /// Request a list of models from the API
@@ -58,14 +169,13 @@ impl OpenAiApi {
         info!(target: "api_events", "Test connection started.");
         // Ask for list of models to check auth
 
+        let config = ConfigManager::new()?;
         let client = reqwest::Client::new();
-        let json = client
+        let request = client
             .get(format!("{}/models", self.uri))
-            .header(reqwest::header::AUTHORIZATION, &self.auth_header)
-            .send()
-            .await?
-            .json::<ModelList>()
-            .await?;
+            .header(reqwest::header::AUTHORIZATION, &self.auth_header);
+        let response = check_status(send_with_retry(request, &config.retry).await?).await?;
+        let json = response.json::<ModelList>().await?;
 
         // Format the number of models and return it
         let model_names: Vec<&str> = json.data.iter().map(|m| m.id.as_ref()).collect();
@@ -74,47 +184,353 @@ impl OpenAiApi {
         Ok(output)
     }
 
+    async fn openai_post_stream(&self, endpoint: &str, body: &str) -> Result<reqwest::Response> {
+        let config = ConfigManager::new()?;
+        let client = reqwest::Client::new();
+        let request = client
+            .post(format!("{}/{endpoint}", self.uri))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(reqwest::header::AUTHORIZATION, &self.auth_header)
+            .body(body.to_string());
+        let response = send_with_retry(request, &config.retry).await?;
+
+        // Errors are returned as a regular JSON body rather than an event stream
+        check_status(response).await
+    }
+
+    async fn openai_post_form(&self, endpoint: &str, form: Form) -> Result<String> {
+        let client = reqwest::Client::new();
+        let request = client
+            .post(format!("{}/{endpoint}", self.uri))
+            .header(reqwest::header::AUTHORIZATION, &self.auth_header)
+            .multipart(form);
+        // A multipart body is streamed and cannot be cloned, so it is sent only once
+        let response = check_status(request.send().await.map_err(ApiError::from)?).await?;
+        Ok(response.text().await.map_err(ApiError::from)?)
+    }
+
     /// Chat prompt from the API
     /// # Errors
     /// Network failure or response deserialization failure
-    pub async fn chat(&self, prompt: String, chat_id: String) -> Result<String> {
+    pub async fn chat(&self, prompt: String, chat_id: String) -> Result<ChatAnswer> {
         info!(target: "api_events", "Chat gen started.");
         debug!(target: "api_events", "Chat prompt: {prompt}");
         if prompt.is_empty() {
             info!(target: "api_events", "No prompt, stopping.");
-            return Ok("Prompt is empty, usage: '/chat [PROMPT HERE]'".to_string());
+            return Ok(ChatAnswer {
+                text: "Prompt is empty, usage: '/chat [PROMPT HERE]'".to_string(),
+                exchange: None,
+            });
+        }
+
+        quota::enforce(&self.caller(&chat_id))?;
+        self.summarize_history(&chat_id).await;
+        let (history, request) = self.prepare_chat(&prompt, &chat_id)?;
+        let question = history.messages.len() - 1;
+        let output = self
+            .answer(&chat_id, history, request, Some(question), None)
+            .await?;
+
+        debug!("Chat output: {}", output.text);
+        Ok(output)
+    }
+
+    /// Chat prompt from the API, streamed as it is generated.
+    /// The text generated so far is published on `progress` every time a new delta arrives,
+    /// the complete text is returned and added to the history once the stream ends.
+    /// # Errors
+    /// Network failure or response deserialization failure
+    pub async fn chat_stream(
+        &self,
+        prompt: String,
+        chat_id: String,
+        progress: watch::Sender<String>,
+    ) -> Result<ChatAnswer> {
+        info!(target: "api_events", "Streaming chat gen started.");
+        debug!(target: "api_events", "Chat prompt: {prompt}");
+        if prompt.is_empty() {
+            info!(target: "api_events", "No prompt, stopping.");
+            return Ok(ChatAnswer {
+                text: "Prompt is empty, usage: '/chat [PROMPT HERE]'".to_string(),
+                exchange: None,
+            });
         }
 
+        quota::enforce(&self.caller(&chat_id))?;
+        self.summarize_history(&chat_id).await;
+        let (history, request) = self.prepare_chat(&prompt, &chat_id)?;
+        let question = history.messages.len() - 1;
+        let output = self
+            .answer(&chat_id, history, request, Some(question), Some(&progress))
+            .await?;
+
+        debug!("Chat output: {}", output.text);
+        Ok(output)
+    }
+
+    /// Complete a prepared request, running the tools the model calls and sending their results
+    /// back until it answers. The calls, their results and the answer are added to the history,
+    /// `question` is the position of the question they answer if it is new.
+    async fn answer(
+        &self,
+        chat_id: &str,
+        mut history: ChatHistory,
+        mut request: ChatRequest,
+        question: Option<usize>,
+        progress: Option<&watch::Sender<String>>,
+    ) -> Result<ChatAnswer> {
+        let config = ConfigManager::new()?;
+        let registry = ToolRegistry::from_config(&config.tools);
+        let provider = chat_provider(&request.model)?;
+
+        let mut round = 0;
+        loop {
+            // The model has to answer once the rounds are used up
+            request.tools = if round < config.tools.max_rounds {
+                registry.specs()
+            } else {
+                Vec::new()
+            };
+            let completion = match progress {
+                Some(progress) => provider.complete_stream(&request, progress).await?,
+                None => provider.complete(&request).await?,
+            };
+            self.record_chat_usage(chat_id, &request, &completion);
+
+            if completion.tool_calls.is_empty() {
+                // Appended under the lock of the history, so the new entry is the last one
+                let history = history.add_entry(chat_id, &Role::Assistant, &completion.content)?;
+                return Ok(ChatAnswer {
+                    exchange: Some(Exchange {
+                        question,
+                        answer: history.messages.len() - 1,
+                        answer_text: completion.content.clone(),
+                    }),
+                    text: completion.content,
+                });
+            }
+
+            for message in run_tools(&registry, chat_id, completion).await {
+                history = history.add_message(chat_id, message.clone())?;
+                request.messages.push(message);
+            }
+            round += 1;
+        }
+    }
+
+    /// Ask the model for a summary of the given messages
+    /// # Errors
+    /// Network failure or response deserialization failure
+    pub async fn summarize(&self, messages: &[MessageChat]) -> Result<String> {
+        info!(target: "api_events", "Summary gen started.");
+        let config = ConfigManager::new()?;
+
+        let transcript: String = messages
+            .iter()
+            .map(|m| format!("{}: {}\n\n", m.role, m.content))
+            .collect();
+
+        self.summary_completion(&config, SUMMARY_PROMPT, transcript)
+            .await
+    }
+
+    /// Summarize a document, a long one part by part with the summaries of the parts merged
+    /// # Errors
+    /// Quota, network failure or response deserialization failure
+    pub async fn summarize_document(&self, document: &DocumentText) -> Result<String> {
+        info!(target: "api_events", "Document summary started.");
+        quota::enforce(&self.caller(""))?;
+        let config = ConfigManager::new()?;
+
+        let parts = split_message(&document.text, config.documents.chunk_chars);
+        let part_count = parts.len();
+        let mut summaries = Vec::with_capacity(part_count);
+        for (number, part) in parts.into_iter().enumerate() {
+            let text = if part_count > 1 {
+                format!(
+                    "{} (part {} of {part_count})\n\n{part}",
+                    document.name,
+                    number + 1
+                )
+            } else {
+                format!("{}\n\n{part}", document.name)
+            };
+            summaries.push(
+                self.summary_completion(&config, DOCUMENT_SUMMARY_PROMPT, text)
+                    .await?,
+            );
+        }
+
+        if summaries.len() == 1 {
+            return Ok(summaries.remove(0));
+        }
+        let text = format!("{}\n\n{}", document.name, summaries.join("\n\n"));
+        self.summary_completion(&config, DOCUMENT_MERGE_PROMPT, text)
+            .await
+    }
+
+    async fn summary_completion(
+        &self,
+        config: &ConfigManager,
+        instructions: &str,
+        text: String,
+    ) -> Result<String> {
+        let model = if config.summary.model.is_empty() {
+            config.chat_model.clone()
+        } else {
+            config.summary.model.clone()
+        };
+
+        let request = ChatRequest {
+            model,
+            messages: vec![
+                MessageChat::new("system", instructions),
+                MessageChat::new("user", &text),
+            ],
+            max_tokens: config.max_tokens,
+            tools: Vec::new(),
+        };
+
+        let completion = chat_provider(&request.model)?.complete(&request).await?;
+        self.record_chat_usage("", &request, &completion);
+        Ok(completion.content)
+    }
+
+    /// Fold the oldest turns of a long chat into a summary, a failure only costs some context
+    async fn summarize_history(&self, chat_id: &str) {
+        let config = match ConfigManager::new() {
+            Ok(config) if config.summary.enabled => config,
+            _ => return,
+        };
+
+        let history = match ChatHistory::new(chat_id) {
+            Ok(history) => history,
+            Err(error) => {
+                warn!("Skipping summary, failed to read history of {chat_id}: {error}");
+                return;
+            }
+        };
+
+        let Some(folded) = history.foldable(
+            config.summary.trigger_messages,
+            config.summary.fold_messages,
+        ) else {
+            return;
+        };
+
+        let folded_count = folded.len();
+        let result = match self.summarize(folded).await {
+            Ok(summary) => history.fold_summary(chat_id, folded_count, &summary),
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            warn!("Failed to summarize history of {chat_id}: {error}");
+        }
+    }
+
+    /// Add the prompt to the history of `chat_id` and form the request for the whole history
+    fn prepare_chat(&self, prompt: &str, chat_id: &str) -> Result<(ChatHistory, ChatRequest)> {
         let config = ConfigManager::new()?;
 
         // Get the message history from the user that called the command
-        let mut history = ChatHistory::new(&chat_id)?;
-        history = history.add_entry(&chat_id, &Role::User, &prompt)?;
+        let mut history = ChatHistory::new(chat_id)?;
+        history =
+            history.add_entry_with_images(chat_id, &Role::User, prompt, self.images.clone())?;
+        let request = Self::request_for(&history, chat_id, config)?;
 
-        // Form the request struct and convert it to a https body in json
-        let messages: Vec<MessageChat> = history.messages.clone();
+        Ok((history, request))
+    }
 
-        let request_data = RequestChat {
-            model: config.chat_model,
-            messages,
+    /// The request for the next answer in a history
+    fn request_for(
+        history: &ChatHistory,
+        chat_id: &str,
+        config: ConfigManager,
+    ) -> Result<ChatRequest> {
+        // Form the request struct from as much history as fits in the context budget
+        let (mut messages, report) = history.within_budget(config.context.token_budget);
+        keep_recent_images(&mut messages, config.vision.history_images);
+        if report.dropped_messages > 0 {
+            info!(
+                target: "api_events",
+                "Context trimmed for {chat_id}: dropped {} messages (~{} tokens), sending ~{} tokens",
+                report.dropped_messages, report.dropped_tokens, report.kept_tokens
+            );
+        }
+
+        // Pictures need a model that can see them
+        let has_images = messages.iter().any(|m| !m.images.is_empty());
+        let model = if has_images && !config.vision.model.is_empty() {
+            config.vision.model
+        } else {
+            config.chat_model
         };
 
-        // Make the request
-        let body = serde_json::to_string(&request_data)?;
-        trace!("Chat request body: {body}");
-        let response = self.openai_post("chat/completions", &body).await?;
-        trace!("Chat response: {response}");
-        let json: ResponseChat = serde_json::from_str(&response)?;
+        Ok(ChatRequest {
+            model,
+            messages,
+            max_tokens: config.max_tokens,
+            tools: Vec::new(),
+        })
+    }
 
-        let output = json.choices[0].message.content.clone();
+    /// Answer the last question of a history again, replacing the answer it got
+    /// # Errors
+    /// Quota, network failure or response deserialization failure
+    pub async fn regenerate(&self, chat_id: String) -> Result<ChatAnswer> {
+        info!(target: "api_events", "Regenerating the last answer.");
+        quota::enforce(&self.caller(&chat_id))?;
 
-        // Add the response back to the history
-        history.add_entry(&chat_id, &Role::Assistant, &output)?;
+        let history = ChatHistory::pop_answer(&chat_id)?;
+        let request = Self::request_for(&history, &chat_id, ConfigManager::new()?)?;
+        let output = self.answer(&chat_id, history, request, None, None).await?;
 
-        debug!("Chat output: {output}");
+        debug!("Chat output: {}", output.text);
         Ok(output)
     }
 
+    /// Have the model go on with the last answer of a history, which is extended with the
+    /// continuation. Returns the continuation only.
+    /// # Errors
+    /// Quota, network failure or response deserialization failure
+    pub async fn continue_answer(&self, chat_id: String) -> Result<String> {
+        info!(target: "api_events", "Continuing the last answer.");
+        quota::enforce(&self.caller(&chat_id))?;
+
+        let config = ConfigManager::new()?;
+        let registry = ToolRegistry::from_config(&config.tools);
+        let history = ChatHistory::new(&chat_id)?;
+        let mut request = Self::request_for(&history, &chat_id, ConfigManager::new()?)?;
+        let provider = chat_provider(&request.model)?;
+        // Asked for but not kept, the history only gets the longer answer
+        request
+            .messages
+            .push(MessageChat::new("user", CONTINUE_PROMPT));
+
+        let mut round = 0;
+        let completion = loop {
+            request.tools = if round < config.tools.max_rounds {
+                registry.specs()
+            } else {
+                Vec::new()
+            };
+            let completion = provider.complete(&request).await?;
+            self.record_chat_usage(&chat_id, &request, &completion);
+            if completion.tool_calls.is_empty() {
+                break completion;
+            }
+            // Like the prompt, the tool calls only help to write the continuation
+            request
+                .messages
+                .extend(run_tools(&registry, &chat_id, completion).await);
+            round += 1;
+        };
+        ChatHistory::extend_answer(&chat_id, &completion.content)?;
+
+        debug!("Chat continuation: {}", completion.content);
+        Ok(completion.content)
+    }
+
     /// Clear the chat history for a given chat ID.
     /// Does not reach out to the API
     /// # Errors
@@ -134,6 +550,72 @@ impl OpenAiApi {
         }
     }
 
+    /// Turn speech into text with a Whisper compatible `audio/transcriptions` endpoint.
+    /// `seconds` is the length of the audio, which the endpoint bills by.
+    /// # Errors
+    /// Quota, network failure or response deserialization failure
+    pub async fn transcribe(
+        &self,
+        audio: Vec<u8>,
+        file_name: &str,
+        seconds: u32,
+    ) -> Result<String> {
+        info!(target: "api_events", "Transcription started.");
+        quota::enforce(&self.caller(""))?;
+        let config = ConfigManager::new()?;
+        let model = config.voice.transcription_model;
+
+        let form = Form::new()
+            .text("model", model.clone())
+            .text("response_format", "json")
+            .part("file", Part::bytes(audio).file_name(file_name.to_string()));
+
+        let response = self.openai_post_form("audio/transcriptions", form).await?;
+        self.record_audio_usage(|config| {
+            UsageRecord::transcription(
+                &self.caller(""),
+                &model,
+                u64::from(seconds),
+                &config.usage.prices,
+            )
+        });
+        trace!("Transcription response: {response}");
+        let json: ResponseTranscription = serde_json::from_str(&response)?;
+
+        debug!("Transcript: {}", json.text);
+        Ok(json.text.trim().to_string())
+    }
+
+    /// Read `text` out with an `audio/speech` endpoint, returns the audio file
+    /// # Errors
+    /// Quota or network failure
+    pub async fn speak(&self, text: &str) -> Result<Vec<u8>> {
+        info!(target: "api_events", "Speech started.");
+        quota::enforce(&self.caller(""))?;
+        let config = ConfigManager::new()?;
+
+        let request = RequestSpeech {
+            model: config.voice.speech_model,
+            input: speech_input(text, config.voice.speech_max_chars),
+            voice: config.voice.speech_voice,
+            response_format: config.voice.speech_format,
+        };
+        let audio = self
+            .openai_post_bytes("audio/speech", &serde_json::to_string(&request)?)
+            .await?;
+        self.record_audio_usage(|config| {
+            UsageRecord::speech(
+                &self.caller(""),
+                &request.model,
+                request.input.chars().count() as u64,
+                &config.usage.prices,
+            )
+        });
+
+        debug!("Speech of {} bytes", audio.len());
+        Ok(audio)
+    }
+
     /// Request an image URL from a prompt from the API
     /// # Errors
     /// OS file errors
@@ -144,23 +626,138 @@ impl OpenAiApi {
             return Ok("Prompt is empty, usage: '/image [PROMPT HERE]'".to_string());
         }
 
+        quota::enforce(&self.caller(""))?;
         let config = ConfigManager::new()?;
 
-        let request_data = OpenAiRequestImage {
+        let request = ImageRequest {
             model: config.image_model,
             prompt,
-            n: 1,
             size: config.image_size,
         };
+
+        let url = image_provider(&request.model)?.generate(&request).await?;
+        self.record_image_usage(&request);
+        Ok(url)
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OpenAiApi {
+    async fn complete(&self, request: &ChatRequest) -> Result<ChatCompletion> {
+        let request_data = RequestChat {
+            model: request.model.clone(),
+            messages: paired_tool_messages(&request.messages)
+                .into_iter()
+                .map(MessageOpenAi::from)
+                .collect(),
+            tools: request.tools.iter().map(ToolOpenAi::from).collect(),
+            stream: false,
+            stream_options: None,
+        };
+
         // Make the request
         let body = serde_json::to_string(&request_data)?;
         trace!("Chat request body: {body}");
-        let response = self.openai_post("images/generations", &body).await?;
+        let response = self.openai_post("chat/completions", &body).await?;
         trace!("Chat response: {response}");
+        let json: ResponseChat = serde_json::from_str(&response)?;
+
+        let usage = json.usage.map(TokenUsage::from);
+        let Some(message) = json.choices.into_iter().next().map(|choice| choice.message) else {
+            return Err(anyhow!("No output found."));
+        };
+        let content = message.content.unwrap_or_default();
+        if content.is_empty() && message.tool_calls.is_empty() {
+            return Err(anyhow!("No output found."));
+        }
+        Ok(ChatCompletion {
+            content,
+            usage,
+            tool_calls: message.tool_calls.into_iter().map(ToolCall::from).collect(),
+        })
+    }
+
+    async fn complete_stream(
+        &self,
+        request: &ChatRequest,
+        progress: &watch::Sender<String>,
+    ) -> Result<ChatCompletion> {
+        let request_data = RequestChat {
+            model: request.model.clone(),
+            messages: paired_tool_messages(&request.messages)
+                .into_iter()
+                .map(MessageOpenAi::from)
+                .collect(),
+            tools: request.tools.iter().map(ToolOpenAi::from).collect(),
+            stream: true,
+            // The last chunk then carries the token usage of the whole stream
+            stream_options: Some(StreamOptions {
+                include_usage: true,
+            }),
+        };
+
+        // Make the request
+        let body = serde_json::to_string(&request_data)?;
+        trace!("Chat request body: {body}");
+        let mut response = self.openai_post_stream("chat/completions", &body).await?;
+
+        let mut decoder = LineDecoder::default();
+        let mut output = String::new();
+        let mut usage = None;
+        let mut tool_calls: Vec<ToolCall> = Vec::new();
+        'stream: while let Some(chunk) = response.chunk().await.map_err(ApiError::from)? {
+            for data in decoder.push_sse(&chunk) {
+                if data == SSE_DONE {
+                    break 'stream;
+                }
+                trace!("Chat stream chunk: {data}");
+                let json: ResponseChatChunk = serde_json::from_str(&data)?;
+                if let Some(chunk_usage) = json.usage {
+                    usage = Some(TokenUsage::from(chunk_usage));
+                }
+                let Some(delta) = json.choices.into_iter().next().map(|c| c.delta) else {
+                    continue;
+                };
+                if let Some(content) = delta.content.filter(|c| !c.is_empty()) {
+                    output.push_str(&content);
+                    progress.send_replace(output.clone());
+                }
+                for chunk in delta.tool_calls {
+                    chunk.merge_into(&mut tool_calls);
+                }
+            }
+        }
+
+        if output.is_empty() && tool_calls.is_empty() {
+            return Err(anyhow!("No output found."));
+        }
+
+        Ok(ChatCompletion {
+            content: output,
+            usage,
+            tool_calls,
+        })
+    }
+}
+
+#[async_trait]
+impl ImageProvider for OpenAiApi {
+    async fn generate(&self, request: &ImageRequest) -> Result<String> {
+        let request_data = OpenAiRequestImage {
+            model: request.model.clone(),
+            prompt: request.prompt.clone(),
+            n: 1,
+            size: request.size.clone(),
+        };
+        // Make the request
+        let body = serde_json::to_string(&request_data)?;
+        trace!("Image request body: {body}");
+        let response = self.openai_post("images/generations", &body).await?;
+        trace!("Image response: {response}");
         let json: ResponseImage = serde_json::from_str(&response)?;
 
         // If we get multiple urls just return the first one
-        match json.data.iter().map(|d| d.url.to_string()).next() {
+        match json.data.into_iter().map(|d| d.url).next() {
             Some(s) => Ok(s),
             None => Err(anyhow!("No output found.")),
         }
@@ -171,19 +768,324 @@ impl OpenAiApi {
 #[derive(Deserialize, Debug)]
 struct ResponseChat {
     choices: Vec<ChoicesChat>,
+    usage: Option<UsageChat>,
+}
+
+#[derive(Deserialize, Debug)]
+struct UsageChat {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+impl From<UsageChat> for TokenUsage {
+    fn from(usage: UsageChat) -> Self {
+        TokenUsage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
 struct ChoicesChat {
-    message: MessageChat,
+    message: ResponseMessageChat,
+}
+
+/// The content is null when the model calls tools
+#[derive(Deserialize, Debug)]
+struct ResponseMessageChat {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCallOpenAi>,
 }
 
 #[derive(Serialize, Debug)]
 struct RequestChat {
     model: String,
-    messages: Vec<MessageChat>,
+    messages: Vec<MessageOpenAi>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolOpenAi>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+#[derive(Serialize, Debug)]
+struct MessageOpenAi {
+    role: String,
+    content: ContentOpenAi,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCallOpenAi>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+/// Plain text, or parts when there are pictures
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum ContentOpenAi {
+    Text(String),
+    Parts(Vec<PartOpenAi>),
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PartOpenAi {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrlOpenAi },
+}
+
+#[derive(Serialize, Debug)]
+struct ImageUrlOpenAi {
+    url: String,
+}
+
+impl From<&MessageChat> for MessageOpenAi {
+    fn from(message: &MessageChat) -> Self {
+        let content = if message.images.is_empty() {
+            ContentOpenAi::Text(message.content.clone())
+        } else {
+            let mut parts = vec![PartOpenAi::Text {
+                text: message.content.clone(),
+            }];
+            parts.extend(message.images.iter().filter_map(|image| {
+                let data = encode_image(image)?;
+                Some(PartOpenAi::ImageUrl {
+                    image_url: ImageUrlOpenAi {
+                        url: format!("data:{};base64,{data}", image.mime_type),
+                    },
+                })
+            }));
+            ContentOpenAi::Parts(parts)
+        };
+        MessageOpenAi {
+            role: message.role.clone(),
+            content,
+            tool_calls: message
+                .tool_calls
+                .iter()
+                .map(ToolCallOpenAi::from)
+                .collect(),
+            tool_call_id: message.tool_call_id.clone(),
+        }
+    }
+}
+
+/// Run the tools a completion called, returns the message with the calls followed by a
+/// message with the result of each one
+async fn run_tools(
+    registry: &ToolRegistry,
+    chat_id: &str,
+    completion: ChatCompletion,
+) -> Vec<MessageChat> {
+    let mut messages = vec![MessageChat {
+        tool_calls: completion.tool_calls.clone(),
+        ..MessageChat::new("assistant", &completion.content)
+    }];
+    for call in &completion.tool_calls {
+        info!(target: "api_events", "Tool {} called for {chat_id}", call.name);
+        messages.push(MessageChat {
+            tool_call_id: Some(call.id.clone()),
+            ..MessageChat::new("tool", &registry.execute(call).await)
+        });
+    }
+    messages
+}
+
+/// The messages without tool calls that lack a result and results that lack their call.
+/// Trimming the history or an interrupted answer can leave them, and the API rejects both.
+fn paired_tool_messages(messages: &[MessageChat]) -> Vec<&MessageChat> {
+    let results: HashSet<&str> = messages
+        .iter()
+        .filter_map(|message| message.tool_call_id.as_deref())
+        .collect();
+    let mut calls: HashSet<&str> = HashSet::new();
+    let mut paired = Vec::new();
+    for message in messages {
+        if let Some(id) = &message.tool_call_id {
+            if !calls.contains(id.as_str()) {
+                continue;
+            }
+        } else if !message.tool_calls.is_empty() {
+            if !message
+                .tool_calls
+                .iter()
+                .all(|call| results.contains(call.id.as_str()))
+            {
+                continue;
+            }
+            calls.extend(message.tool_calls.iter().map(|call| call.id.as_str()));
+        }
+        paired.push(message);
+    }
+    paired
+}
+
+#[derive(Serialize, Debug)]
+struct ToolOpenAi {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    function: FunctionOpenAi,
+}
+
+#[derive(Serialize, Debug)]
+struct FunctionOpenAi {
+    name: String,
+    description: String,
+    parameters: serde_json::Value,
+}
+
+impl From<&ToolSpec> for ToolOpenAi {
+    fn from(tool: &ToolSpec) -> Self {
+        ToolOpenAi {
+            kind: "function",
+            function: FunctionOpenAi {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                parameters: tool.parameters.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ToolCallOpenAi {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    function: FunctionCallOpenAi,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FunctionCallOpenAi {
+    name: String,
+    arguments: String,
+}
+
+impl From<&ToolCall> for ToolCallOpenAi {
+    fn from(call: &ToolCall) -> Self {
+        ToolCallOpenAi {
+            id: call.id.clone(),
+            kind: "function".to_string(),
+            function: FunctionCallOpenAi {
+                name: call.name.clone(),
+                arguments: call.arguments.clone(),
+            },
+        }
+    }
+}
+
+impl From<ToolCallOpenAi> for ToolCall {
+    fn from(call: ToolCallOpenAi) -> Self {
+        ToolCall {
+            id: call.id,
+            name: call.function.name,
+            arguments: call.function.arguments,
+        }
+    }
+}
+
+/// At most `max_chars` of `text`, cut at the end of a sentence or word if there is one
+fn speech_input(text: &str, max_chars: usize) -> String {
+    let Some((cut, _)) = text.char_indices().nth(max_chars) else {
+        return text.to_string();
+    };
+    let head = &text[..cut];
+    let end = head
+        .rfind(['.', '!', '?', '\n'])
+        .map(|at| at + 1)
+        .or_else(|| head.rfind(' '))
+        .unwrap_or(cut);
+    head[..end].trim_end().to_string()
+}
+
+#[derive(Serialize, Debug)]
+struct RequestSpeech {
+    model: String,
+    input: String,
+    voice: String,
+    response_format: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct ResponseTranscription {
+    text: String,
+}
+
+#[derive(Serialize, Debug)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+// Structs for streamed chat generation
+#[derive(Deserialize, Debug)]
+struct ResponseChatChunk {
+    choices: Vec<ChoicesChatChunk>,
+    usage: Option<UsageChat>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChoicesChatChunk {
+    delta: DeltaChat,
+}
+
+#[derive(Deserialize, Debug)]
+struct DeltaChat {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCallChunk>,
+}
+
+/// A piece of a streamed tool call, the arguments arrive a few characters at a time
+#[derive(Deserialize, Debug)]
+struct ToolCallChunk {
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionCallChunk>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FunctionCallChunk {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+impl ToolCallChunk {
+    fn merge_into(self, calls: &mut Vec<ToolCall>) {
+        while calls.len() <= self.index {
+            calls.push(ToolCall {
+                id: String::new(),
+                name: String::new(),
+                arguments: String::new(),
+            });
+        }
+        let call = &mut calls[self.index];
+        if let Some(id) = self.id {
+            call.id = id;
+        }
+        if let Some(function) = self.function {
+            call.name.push_str(&function.name.unwrap_or_default());
+            call.arguments
+                .push_str(&function.arguments.unwrap_or_default());
+        }
+    }
+}
+
+// Asks for more of the last answer, the reply is added to that answer
+const CONTINUE_PROMPT: &str = "Continue your last answer exactly where it stopped. Do not repeat anything you already wrote and do not comment on continuing.";
+
+// Instructions for summarizing a document, long ones part by part
+const DOCUMENT_SUMMARY_PROMPT: &str = "Summarize the following document for someone who has not read it. Keep its purpose, main points, names, numbers and conclusions. Start with the file name it is given under. Reply with the summary only.";
+
+const DOCUMENT_MERGE_PROMPT: &str = "The following are summaries of consecutive parts of one document. Merge them into a single summary of the whole document without repeating points. Reply with the summary only.";
+
+// Instructions for folding old turns, the result is stored as a system message
+const SUMMARY_PROMPT: &str = "Summarize the following conversation between a user and an assistant so it can replace the original messages. Keep names, facts, decisions, open questions and any instructions about the assistant's persona or behavior. If the conversation starts with an earlier summary, merge it into the new one. Reply with the summary only.";
+
+/// Payload that marks the end of a chat completion stream
+const SSE_DONE: &str = "[DONE]";
+
 // Structs for image generation
 #[derive(Deserialize, Debug)]
 struct ChoicesImage {
@@ -218,6 +1120,278 @@ struct Model {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_image_content_parts() {
+        let path = "test-openai-image.jpg";
+        std::fs::write(path, b"jpeg").unwrap();
+        let message = MessageChat {
+            message_id: Some(5),
+            images: vec![ImageRef {
+                path: path.to_string(),
+                mime_type: "image/jpeg".to_string(),
+            }],
+            ..MessageChat::new("user", "What is this?")
+        };
+        let json = serde_json::to_value(MessageOpenAi::from(&message)).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "What is this?"},
+                    {"type": "image_url", "image_url": {"url": "data:image/jpeg;base64,anBlZw=="}}
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_stream_chunk_deltas() {
+        let mut decoder = LineDecoder::default();
+        let stream = concat!(
+            "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"lo\"}}]}\n\n",
+        );
+        let output: String = decoder
+            .push_sse(stream.as_bytes())
+            .iter()
+            .map(|data| serde_json::from_str::<ResponseChatChunk>(data).unwrap())
+            .filter_map(|chunk| chunk.choices[0].delta.content.clone())
+            .collect();
+        assert_eq!(output, "Hello");
+    }
+
+    #[test]
+    fn test_stream_tool_call_deltas() {
+        let mut decoder = LineDecoder::default();
+        let stream = concat!(
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",\"function\":{\"name\":\"calculator\",\"arguments\":\"\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"expression\\\"\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\":\\\"6*7\\\"}\"}}]}}]}\n\n",
+        );
+        let mut calls = Vec::new();
+        for data in decoder.push_sse(stream.as_bytes()) {
+            let chunk: ResponseChatChunk = serde_json::from_str(&data).unwrap();
+            for call in chunk.choices.into_iter().flat_map(|c| c.delta.tool_calls) {
+                call.merge_into(&mut calls);
+            }
+        }
+        assert_eq!(
+            calls,
+            vec![ToolCall {
+                id: "call_1".to_string(),
+                name: "calculator".to_string(),
+                arguments: r#"{"expression":"6*7"}"#.to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_paired_tool_messages() {
+        let call = |id: &str| ToolCall {
+            id: id.to_string(),
+            name: "calculator".to_string(),
+            arguments: "{}".to_string(),
+        };
+        let message =
+            |role: &str, tool_calls: Vec<ToolCall>, tool_call_id: Option<&str>| MessageChat {
+                tool_calls,
+                tool_call_id: tool_call_id.map(str::to_string),
+                ..MessageChat::new(role, "")
+            };
+        let messages = vec![
+            // The call of this result was trimmed away
+            message("tool", Vec::new(), Some("old")),
+            message("assistant", vec![call("a")], None),
+            message("tool", Vec::new(), Some("a")),
+            // An interrupted answer never got its result
+            message("assistant", vec![call("b")], None),
+            message("user", Vec::new(), None),
+        ];
+        let paired = paired_tool_messages(&messages);
+        assert_eq!(paired.len(), 3);
+        assert_eq!(paired[0].tool_calls[0].id, "a");
+        assert_eq!(paired[1].tool_call_id.as_deref(), Some("a"));
+        assert_eq!(paired[2].role, "user");
+    }
+
+    #[tokio::test]
+    async fn test_complete_returns_tool_calls() {
+        use wiremock::matchers::{body_string_contains, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(body_string_contains(r#""tools":[{"type":"function""#))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"choices":[{"message":{"role":"assistant","content":null,"tool_calls":[{"id":"call_1","type":"function","function":{"name":"calculator","arguments":"{\"expression\":\"6*7\"}"}}]}}]}"#,
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let openai_api = OpenAiApi {
+            uri: server.uri(),
+            auth_header: "Bearer test".to_string(),
+            caller: None,
+            images: Vec::new(),
+        };
+        let request = ChatRequest {
+            model: "gpt-4o".to_string(),
+            messages: vec![],
+            max_tokens: 16,
+            tools: ToolRegistry::from_config(&crate::config_manager::ToolConfig::default()).specs(),
+        };
+        let completion = openai_api.complete(&request).await.unwrap();
+        assert!(completion.content.is_empty());
+        assert_eq!(completion.tool_calls[0].name, "calculator");
+        assert_eq!(
+            completion.tool_calls[0].arguments,
+            r#"{"expression":"6*7"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_complete_error_is_typed() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(401).set_body_string(
+                r#"{"error":{"message":"Incorrect API key provided","type":"invalid_request_error","code":"invalid_api_key"}}"#,
+            ))
+            .mount(&server)
+            .await;
+
+        let openai_api = OpenAiApi {
+            uri: server.uri(),
+            auth_header: "Bearer wrong".to_string(),
+            caller: None,
+            images: Vec::new(),
+        };
+        let request = ChatRequest {
+            model: "gpt-4o".to_string(),
+            messages: vec![],
+            max_tokens: 16,
+            tools: Vec::new(),
+        };
+        let error = openai_api.complete(&request).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ApiError>(),
+            Some(ApiError::Auth(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_complete_retries_rate_limit() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "0"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"choices":[{"message":{"role":"assistant","content":"Hi!"}}]}"#,
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let openai_api = OpenAiApi {
+            uri: server.uri(),
+            auth_header: "Bearer test".to_string(),
+            caller: None,
+            images: Vec::new(),
+        };
+        let request = ChatRequest {
+            model: "gpt-4o".to_string(),
+            messages: vec![MessageChat::new("user", "Hello!")],
+            max_tokens: 16,
+            tools: Vec::new(),
+        };
+        let completion = openai_api.complete(&request).await.unwrap();
+        assert_eq!(completion.content, "Hi!");
+    }
+
+    #[tokio::test]
+    async fn test_transcribe_posts_audio() {
+        use wiremock::matchers::{body_string_contains, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/audio/transcriptions"))
+            .and(body_string_contains("filename=\"voice.ogg\""))
+            .and(body_string_contains("whisper-1"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(r#"{"text":" Hello there. "}"#),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let openai_api = OpenAiApi {
+            uri: server.uri(),
+            auth_header: "Bearer test".to_string(),
+            caller: None,
+            images: Vec::new(),
+        };
+        let transcript = openai_api
+            .transcribe(b"OggS".to_vec(), "voice.ogg", 3)
+            .await
+            .unwrap();
+        assert_eq!(transcript, "Hello there.");
+    }
+
+    #[test]
+    fn test_speech_input() {
+        assert_eq!(speech_input("Short answer.", 100), "Short answer.");
+        assert_eq!(
+            speech_input("First sentence. Second sentence.", 20),
+            "First sentence."
+        );
+        assert_eq!(speech_input("no sentence end here", 12), "no sentence");
+        assert_eq!(speech_input("ääääää", 3), "äää");
+    }
+
+    #[tokio::test]
+    async fn test_speak_returns_audio() {
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/audio/speech"))
+            .and(body_partial_json(serde_json::json!({
+                "input": "Hello!",
+                "response_format": "opus",
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"OggS".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let openai_api = OpenAiApi {
+            uri: server.uri(),
+            auth_header: "Bearer test".to_string(),
+            caller: None,
+            images: Vec::new(),
+        };
+        assert_eq!(openai_api.speak("Hello!").await.unwrap(), b"OggS");
+    }
+
     #[test]
     fn test_api_env_vars() {
         // This is not always a fail state, sometimes env vars could come from somewhere else
@@ -263,7 +1437,7 @@ mod tests {
             .chat(prompt.clone(), chat_id.clone())
             .await
             .unwrap();
-        assert!(!response.is_empty());
+        assert!(!response.text.is_empty());
     }
 
     #[tokio::test]
@@ -275,7 +1449,11 @@ mod tests {
             .chat(prompt.clone(), chat_id.clone())
             .await
             .unwrap();
-        assert_eq!(response, "Prompt is empty, usage: '/chat [PROMPT HERE]'");
+        assert_eq!(
+            response.text,
+            "Prompt is empty, usage: '/chat [PROMPT HERE]'"
+        );
+        assert!(response.exchange.is_none());
     }
 
     #[tokio::test]
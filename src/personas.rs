@@ -0,0 +1,213 @@
+use super::chat_settings::{ChatSettings, ChatSettingsStore};
+use super::config_manager::PersonaConfig;
+use super::named_conversations::parse_name;
+
+use anyhow::{bail, Result};
+
+// Words of `/persona` that cannot be persona names
+const SUBCOMMANDS: [&str; 4] = ["list", "show", "save", "delete"];
+
+/// What `/persona` was asked to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PersonaCommand {
+    /// Start the conversation over with the prompt of a persona
+    Use(String),
+    List,
+    /// The prompt of a persona, the prompt of the conversation without a name
+    Show(Option<String>),
+    /// Save a prompt for the chat, the prompt of the conversation if it is empty
+    Save {
+        name: String,
+        prompt: String,
+    },
+    Delete(String),
+    Usage,
+}
+
+/// Read the argument of `/persona`
+#[must_use]
+pub fn parse_command(argument: &str) -> PersonaCommand {
+    let argument = argument.trim();
+    let (word, rest) = argument
+        .split_once(char::is_whitespace)
+        .unwrap_or((argument, ""));
+    let rest = rest.trim();
+    let name = || rest.to_lowercase();
+
+    match word.to_lowercase().as_str() {
+        "" => PersonaCommand::Usage,
+        "list" => PersonaCommand::List,
+        "show" if rest.is_empty() => PersonaCommand::Show(None),
+        "show" => PersonaCommand::Show(Some(name())),
+        "save" | "delete" if rest.is_empty() => PersonaCommand::Usage,
+        "save" => {
+            let (name, prompt) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            PersonaCommand::Save {
+                name: name.to_lowercase(),
+                prompt: prompt.trim().to_string(),
+            }
+        }
+        "delete" => PersonaCommand::Delete(name()),
+        persona => PersonaCommand::Use(persona.to_string()),
+    }
+}
+
+/// The prompt of persona `name`, the ones saved by the chat go before the config file
+#[must_use]
+pub fn find<'a>(
+    config: &'a PersonaConfig,
+    settings: &'a ChatSettings,
+    name: &str,
+) -> Option<&'a str> {
+    settings
+        .personas
+        .get(name)
+        .or_else(|| config.prompts.get(name))
+        .map(String::as_str)
+}
+
+/// The names of every persona a chat can use in order, `true` for the ones it saved
+#[must_use]
+pub fn names<'a>(config: &'a PersonaConfig, settings: &'a ChatSettings) -> Vec<(&'a str, bool)> {
+    let mut names: Vec<(&str, bool)> = settings
+        .personas
+        .keys()
+        .map(|name| (name.as_str(), true))
+        .chain(
+            config
+                .prompts
+                .keys()
+                .filter(|name| !settings.personas.contains_key(*name))
+                .map(|name| (name.as_str(), false)),
+        )
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+/// Save `prompt` as persona `name` of a chat, replacing one it saved before
+/// # Errors
+/// The name is not valid, the chat saved `max` personas already or settings errors
+pub fn save(
+    store: &ChatSettingsStore,
+    chat_id: &str,
+    name: &str,
+    prompt: &str,
+    max: usize,
+) -> Result<()> {
+    let name = parse_name(name)?;
+    if SUBCOMMANDS.contains(&name.as_str()) {
+        bail!("'{name}' is a /persona command and cannot be a persona name");
+    }
+    if prompt.trim().is_empty() {
+        bail!("The persona needs a prompt");
+    }
+
+    let mut full = false;
+    store.update(chat_id, |settings| {
+        if !settings.personas.contains_key(&name) && settings.personas.len() >= max {
+            full = true;
+        } else {
+            settings
+                .personas
+                .insert(name.clone(), prompt.trim().to_string());
+        }
+    })?;
+    if full {
+        bail!("This chat saved {max} personas already, /persona delete one first");
+    }
+    Ok(())
+}
+
+/// Remove a persona the chat saved, the ones in the config file stay
+/// # Errors
+/// The chat did not save the persona or settings errors
+pub fn delete(store: &ChatSettingsStore, chat_id: &str, name: &str) -> Result<()> {
+    let mut found = false;
+    store.update(chat_id, |settings| {
+        found = settings.personas.remove(name).is_some();
+    })?;
+    if !found {
+        bail!("This chat did not save a persona named '{name}'");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(""), PersonaCommand::Usage);
+        assert_eq!(parse_command(" list "), PersonaCommand::List);
+        assert_eq!(parse_command("show"), PersonaCommand::Show(None));
+        assert_eq!(
+            parse_command("show Coder"),
+            PersonaCommand::Show(Some("coder".to_string()))
+        );
+        assert_eq!(
+            parse_command("save Pirate Talk like a pirate."),
+            PersonaCommand::Save {
+                name: "pirate".to_string(),
+                prompt: "Talk like a pirate.".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_command("save pirate"),
+            PersonaCommand::Save {
+                name: "pirate".to_string(),
+                prompt: String::new(),
+            }
+        );
+        assert_eq!(parse_command("save"), PersonaCommand::Usage);
+        assert_eq!(
+            parse_command("delete pirate"),
+            PersonaCommand::Delete("pirate".to_string())
+        );
+        assert_eq!(
+            parse_command("Translator"),
+            PersonaCommand::Use("translator".to_string())
+        );
+    }
+
+    #[test]
+    fn test_save_find_delete() {
+        let path = Path::new("test-personas.json");
+        let store = ChatSettingsStore::new(path);
+        let chat_id = "test-personas";
+        let config = PersonaConfig {
+            prompts: HashMap::from([
+                ("coder".to_string(), "Write code".to_string()),
+                ("tutor".to_string(), "Teach".to_string()),
+            ]),
+            max_saved: 2,
+        };
+
+        save(&store, chat_id, "pirate", "Talk like a pirate", 2).unwrap();
+        // Saved personas go before the ones in the config file
+        save(&store, chat_id, "coder", "Write Rust", 2).unwrap();
+        assert!(save(&store, chat_id, "poet", "Rhyme", 2).is_err());
+        assert!(save(&store, chat_id, "list", "Nope", 2).is_err());
+        assert!(save(&store, chat_id, "bad name", "Nope", 2).is_err());
+
+        let settings = store.get(chat_id).unwrap();
+        assert_eq!(find(&config, &settings, "coder"), Some("Write Rust"));
+        assert_eq!(find(&config, &settings, "tutor"), Some("Teach"));
+        assert_eq!(find(&config, &settings, "poet"), None);
+        assert_eq!(
+            names(&config, &settings),
+            vec![("coder", true), ("pirate", true), ("tutor", false)]
+        );
+
+        delete(&store, chat_id, "coder").unwrap();
+        assert!(delete(&store, chat_id, "tutor").is_err());
+        let settings = store.get(chat_id).unwrap();
+        assert_eq!(find(&config, &settings, "coder"), Some("Write code"));
+
+        fs::remove_file(path).unwrap();
+    }
+}
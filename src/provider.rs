@@ -0,0 +1,253 @@
+use super::anthropic_api::AnthropicApi;
+use super::chat_history::{MessageChat, ToolCall};
+use super::config_manager::{ConfigManager, ProviderKind};
+use super::ollama_api::OllamaApi;
+use super::open_ai_api::OpenAiApi;
+use super::tools::ToolSpec;
+use super::usage::TokenUsage;
+
+use anyhow::{anyhow, Result};
+
+use async_trait::async_trait;
+
+use tokio::sync::watch;
+
+/// A chat completion request in a form every backend can translate
+#[derive(Debug, Clone)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<MessageChat>,
+    pub max_tokens: u32,
+    /// Tools the model may call instead of answering, backends without tools leave them out
+    pub tools: Vec<ToolSpec>,
+}
+
+/// The answer of a backend to a `ChatRequest`
+#[derive(Debug, Clone, Default)]
+pub struct ChatCompletion {
+    pub content: String,
+    /// Tokens billed for the request, if the backend reported them
+    pub usage: Option<TokenUsage>,
+    /// Tools the model called, the content may be empty then
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// An image generation request
+#[derive(Debug, Clone)]
+pub struct ImageRequest {
+    pub model: String,
+    pub prompt: String,
+    pub size: String,
+}
+
+/// A backend that can answer chat conversations
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    /// Generate the next assistant message
+    /// # Errors
+    /// Network failure or response deserialization failure
+    async fn complete(&self, request: &ChatRequest) -> Result<ChatCompletion>;
+
+    /// Generate the next assistant message, publishing the text generated so far on `progress`.
+    /// Backends that cannot stream publish the whole answer at once.
+    /// # Errors
+    /// Network failure or response deserialization failure
+    async fn complete_stream(
+        &self,
+        request: &ChatRequest,
+        progress: &watch::Sender<String>,
+    ) -> Result<ChatCompletion> {
+        let completion = self.complete(request).await?;
+        progress.send_replace(completion.content.clone());
+        Ok(completion)
+    }
+}
+
+/// A backend that can generate images from a prompt
+#[async_trait]
+pub trait ImageProvider: Send + Sync {
+    /// Generate an image and return its URL
+    /// # Errors
+    /// Network failure or response deserialization failure
+    async fn generate(&self, request: &ImageRequest) -> Result<String>;
+}
+
+/// Which backend serves `model`, the first matching route in the config file wins
+/// # Errors
+/// Config file errors
+pub fn provider_kind(model: &str) -> Result<ProviderKind> {
+    let config = ConfigManager::new()?;
+    Ok(config
+        .providers
+        .routes
+        .iter()
+        .find(|route| model.starts_with(&route.model_prefix))
+        .map_or(config.providers.default, |route| route.provider))
+}
+
+/// The chat backend for `model`
+/// # Errors
+/// Config file errors or missing credentials of the backend
+pub fn chat_provider(model: &str) -> Result<Box<dyn ChatProvider>> {
+    Ok(match provider_kind(model)? {
+        ProviderKind::OpenAi => Box::new(OpenAiApi::new()),
+        ProviderKind::Ollama => Box::new(OllamaApi::new()?),
+        ProviderKind::Anthropic => Box::new(AnthropicApi::new()?),
+    })
+}
+
+/// The image backend for `model`
+/// # Errors
+/// Config file errors or a backend without image generation
+pub fn image_provider(model: &str) -> Result<Box<dyn ImageProvider>> {
+    match provider_kind(model)? {
+        ProviderKind::OpenAi => Ok(Box::new(OpenAiApi::new())),
+        kind => Err(anyhow!("{kind:?} does not support image generation.")),
+    }
+}
+
+/// Splits a streamed response body into lines.
+/// Network chunks can end anywhere, so incomplete lines are kept until the rest arrives.
+#[derive(Default, Debug)]
+pub(crate) struct LineDecoder {
+    buffer: Vec<u8>,
+}
+
+impl LineDecoder {
+    /// Complete lines received so far, without their line endings
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut lines = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            lines.push(line.trim_end_matches(['\r', '\n']).to_string());
+        }
+        lines
+    }
+
+    /// Payloads of the complete server-sent event `data:` lines received so far.
+    /// Comments, event names and blank separators carry no content and are skipped.
+    pub fn push_sse(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.push(chunk)
+            .into_iter()
+            .filter_map(|line| {
+                let data = line.strip_prefix("data:")?;
+                Some(data.strip_prefix(' ').unwrap_or(data).to_string())
+            })
+            .collect()
+    }
+}
+
+/// The messages as backends expect them, without the Telegram bookkeeping of the history
+#[must_use]
+pub fn wire_messages(messages: &[MessageChat]) -> Vec<MessageChat> {
+    messages
+        .iter()
+        .map(|message| MessageChat {
+            message_id: None,
+            ..message.clone()
+        })
+        .collect()
+}
+
+/// The messages without tool calls and their results, for backends that are not sent tools.
+/// The answers the model gave after calling tools stay.
+#[must_use]
+pub fn without_tool_turns(messages: &[MessageChat]) -> Vec<MessageChat> {
+    messages
+        .iter()
+        .filter(|message| message.role != "tool")
+        .filter(|message| message.tool_calls.is_empty() || !message.content.is_empty())
+        .map(|message| MessageChat {
+            tool_calls: Vec::new(),
+            ..message.clone()
+        })
+        .collect()
+}
+
+/// Merge the system messages of a history into a single prompt and the rest into alternating
+/// user and assistant turns, as required by backends without a system role in the messages
+#[must_use]
+pub fn split_system(messages: &[MessageChat]) -> (String, Vec<MessageChat>) {
+    let mut system: Vec<&str> = Vec::new();
+    let mut turns: Vec<MessageChat> = Vec::new();
+    for message in messages {
+        if message.role == "system" {
+            system.push(&message.content);
+            continue;
+        }
+        match turns.last_mut() {
+            Some(last) if last.role == message.role => {
+                last.content.push_str("\n\n");
+                last.content.push_str(&message.content);
+                last.images.extend(message.images.iter().cloned());
+            }
+            _ => turns.extend(wire_messages(std::slice::from_ref(message))),
+        }
+    }
+    (system.join("\n\n"), turns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_decoder_split_chunks() {
+        let mut decoder = LineDecoder::default();
+        assert!(decoder.push_sse(b"data: {\"a\":").is_empty());
+        assert_eq!(
+            decoder.push_sse(b"1}\r\n\n: keep-alive\nevent: ping\ndata: [DONE]\n"),
+            vec!["{\"a\":1}".to_string(), "[DONE]".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_line_decoder_lines() {
+        let mut decoder = LineDecoder::default();
+        assert_eq!(
+            decoder.push(b"{\"done\":false}\n{\"do"),
+            vec!["{\"done\":false}"]
+        );
+        assert_eq!(decoder.push(b"ne\":true}\n"), vec!["{\"done\":true}"]);
+    }
+
+    #[test]
+    fn test_split_system() {
+        let (system, turns) = split_system(&[
+            MessageChat::new("system", "Base prompt"),
+            MessageChat::new("system", "Summary"),
+            MessageChat::new("user", "One"),
+            MessageChat::new("user", "Two"),
+            MessageChat::new("assistant", "Answer"),
+        ]);
+        assert_eq!(system, "Base prompt\n\nSummary");
+        assert_eq!(turns.len(), 2);
+        assert_eq!(turns[0].content, "One\n\nTwo");
+        assert_eq!(turns[1].role, "assistant");
+    }
+
+    #[test]
+    fn test_without_tool_turns() {
+        let mut calls = MessageChat::new("assistant", "");
+        calls.tool_calls = vec![ToolCall {
+            id: "call_1".to_string(),
+            name: "calculator".to_string(),
+            arguments: "{}".to_string(),
+        }];
+        let mut result = MessageChat::new("tool", "42");
+        result.tool_call_id = Some("call_1".to_string());
+
+        let turns = without_tool_turns(&[
+            MessageChat::new("user", "What is 6*7?"),
+            calls,
+            result,
+            MessageChat::new("assistant", "It is 42."),
+        ]);
+        let roles: Vec<&str> = turns.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, vec!["user", "assistant"]);
+        assert_eq!(turns[1].content, "It is 42.");
+    }
+}
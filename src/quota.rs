@@ -0,0 +1,401 @@
+use super::config_manager::{Budget, ConfigManager, QuotaConfig};
+use super::usage::{day_start, month_start, Caller, UsageLedger, UsageScope, UsageTotals};
+
+use anyhow::{anyhow, Result};
+
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+
+use serde_derive::{Deserialize, Serialize};
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+// How long a grant lasts when the admin does not say
+const DEFAULT_GRANT_HOURS: i64 = 24;
+
+// Longest override /grant gives, a year
+const MAX_GRANT_HOURS: i64 = 24 * 366;
+
+// Grants from concurrent commands must not overwrite each other
+static OVERRIDES_LOCK: Mutex<()> = Mutex::new(());
+
+/// Whose budget ran out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaScope {
+    User,
+    Chat,
+    Global,
+}
+
+/// Which budget ran out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Daily,
+    Monthly,
+}
+
+impl Period {
+    fn start(self, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Period::Daily => day_start(now),
+            Period::Monthly => month_start(now),
+        }
+    }
+
+    fn reset(self, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Period::Daily => day_start(now) + Duration::days(1),
+            Period::Monthly => {
+                let (year, month) = if now.month() == 12 {
+                    (now.year() + 1, 1)
+                } else {
+                    (now.year(), now.month() + 1)
+                };
+                Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0)
+                    .single()
+                    .unwrap_or(now)
+            }
+        }
+    }
+}
+
+/// A request was refused because a budget is used up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub scope: QuotaScope,
+    pub period: Period,
+    pub resets_at: DateTime<Utc>,
+}
+
+impl QuotaExceeded {
+    /// A polite refusal for the chat, with when to come back
+    #[must_use]
+    pub fn user_message(&self) -> String {
+        let whose = match self.scope {
+            QuotaScope::User => "You have used up your",
+            QuotaScope::Chat => "This chat has used up its",
+            QuotaScope::Global => "The bot has used up its",
+        };
+        let period = match self.period {
+            Period::Daily => "daily",
+            Period::Monthly => "monthly",
+        };
+        format!(
+            "Sorry, {whose} {period} budget. It resets at {}, or an admin can /grant an override.",
+            self.resets_at.format("%Y-%m-%d %H:%M UTC")
+        )
+    }
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {:?} quota exceeded until {}",
+            self.scope, self.period, self.resets_at
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// Whether any limit of `budget` has been reached
+fn exhausted(budget: &Budget, totals: &UsageTotals) -> bool {
+    budget.tokens.is_some_and(|limit| totals.tokens() >= limit)
+        || budget.images.is_some_and(|limit| totals.images >= limit)
+        || budget.cost.is_some_and(|limit| totals.cost >= limit)
+}
+
+/// Refuse with `QuotaExceeded` when a budget that applies to `caller` is used up,
+/// unless an admin granted an override that is still running
+/// # Errors
+/// `QuotaExceeded` or ledger read errors
+pub fn check_quota(
+    caller: &Caller,
+    quotas: &QuotaConfig,
+    ledger: &UsageLedger,
+    overrides: &QuotaOverrides,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    if overrides.is_exempt(caller, now)? {
+        return Ok(());
+    }
+
+    let mut scopes = vec![(
+        QuotaScope::Chat,
+        UsageScope::Chat(&caller.chat_id),
+        &quotas.chat,
+    )];
+    if let Some(user_id) = caller.user_id {
+        scopes.push((QuotaScope::User, UsageScope::User(user_id), &quotas.user));
+    }
+    scopes.push((QuotaScope::Global, UsageScope::Global, &quotas.global));
+
+    for (scope, usage_scope, limits) in scopes {
+        for (period, budget) in [
+            (Period::Daily, &limits.daily),
+            (Period::Monthly, &limits.monthly),
+        ] {
+            // Reading the ledger is only worth it for budgets that are set
+            if *budget == Budget::default() {
+                continue;
+            }
+            let totals = ledger.totals(usage_scope, period.start(now))?;
+            if exhausted(budget, &totals) {
+                return Err(QuotaExceeded {
+                    scope,
+                    period,
+                    resets_at: period.reset(now),
+                }
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check the quotas of `caller` with the ledger and overrides named in the config file
+/// # Errors
+/// `QuotaExceeded`, config file or ledger read errors
+pub fn enforce(caller: &Caller) -> Result<()> {
+    let config = ConfigManager::new()?;
+    check_quota(
+        caller,
+        &config.quotas,
+        &UsageLedger::new(Path::new(&config.usage.path)),
+        &QuotaOverrides::new(Path::new(&config.quotas.overrides_path)),
+        Utc::now(),
+    )
+}
+
+/// Who an override lifts the quotas for
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverrideTarget {
+    User(u64),
+    Chat(String),
+}
+
+impl fmt::Display for OverrideTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverrideTarget::User(user_id) => write!(f, "user {user_id}"),
+            OverrideTarget::Chat(_) => write!(f, "this chat"),
+        }
+    }
+}
+
+/// A temporary lift of all quotas
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuotaOverride {
+    pub target: OverrideTarget,
+    /// Unix time in seconds
+    pub expires_at: i64,
+}
+
+/// Overrides granted by admins, kept in a JSON file
+pub struct QuotaOverrides {
+    path: PathBuf,
+}
+
+impl QuotaOverrides {
+    #[must_use]
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// The overrides file named in the config file
+    /// # Errors
+    /// Config file errors
+    pub fn open() -> Result<Self> {
+        let config = ConfigManager::new()?;
+        Ok(Self::new(Path::new(&config.quotas.overrides_path)))
+    }
+
+    fn load(&self) -> Result<Vec<QuotaOverride>> {
+        if !self.path.is_file() {
+            return Ok(Vec::new());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(&self.path)?)?)
+    }
+
+    /// Lift the quotas of `target` until `expires_at`, dropping overrides that ran out
+    /// # Errors
+    /// OS file errors
+    pub fn grant(&self, target: OverrideTarget, expires_at: DateTime<Utc>) -> Result<()> {
+        let _guard = OVERRIDES_LOCK
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = Utc::now().timestamp();
+        let mut overrides = self.load()?;
+        overrides.retain(|o| o.expires_at > now && o.target != target);
+        overrides.push(QuotaOverride {
+            target,
+            expires_at: expires_at.timestamp(),
+        });
+
+        let tmp_path = self
+            .path
+            .with_extension(format!("json.{}.tmp", std::process::id()));
+        fs::write(&tmp_path, serde_json::to_string_pretty(&overrides)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Whether the chat or the user of `caller` has an override running at `now`
+    /// # Errors
+    /// OS file read errors
+    pub fn is_exempt(&self, caller: &Caller, now: DateTime<Utc>) -> Result<bool> {
+        let now = now.timestamp();
+        Ok(self.load()?.iter().any(|o| {
+            o.expires_at > now
+                && match &o.target {
+                    OverrideTarget::User(user_id) => caller.user_id == Some(*user_id),
+                    OverrideTarget::Chat(chat_id) => caller.chat_id == *chat_id,
+                }
+        }))
+    }
+}
+
+/// Parse the arguments of `/grant`: `chat` or a user ID, then optionally the hours it lasts
+/// # Errors
+/// Malformed arguments
+pub fn parse_grant(args: &str, chat_id: &str) -> Result<(OverrideTarget, Duration)> {
+    let usage = "usage: '/grant [chat|USER ID] [HOURS]'";
+    let mut words = args.split_whitespace();
+
+    let target = match words.next() {
+        Some("chat") => OverrideTarget::Chat(chat_id.to_string()),
+        Some(user_id) => OverrideTarget::User(
+            user_id
+                .parse()
+                .map_err(|_| anyhow!("Unknown target '{user_id}', {usage}"))?,
+        ),
+        None => return Err(anyhow!("No target given, {usage}")),
+    };
+
+    let hours = match words.next() {
+        Some(hours) => hours
+            .parse::<i64>()
+            .ok()
+            .filter(|hours| (1..=MAX_GRANT_HOURS).contains(hours))
+            .ok_or_else(|| {
+                anyhow!("Invalid number of hours '{hours}', at most {MAX_GRANT_HOURS}, {usage}")
+            })?,
+        None => DEFAULT_GRANT_HOURS,
+    };
+
+    let duration = Duration::try_hours(hours)
+        .ok_or_else(|| anyhow!("Invalid number of hours '{hours}', {usage}"))?;
+    Ok((target, duration))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_manager::QuotaLimits;
+    use crate::usage::{TokenUsage, UsageRecord};
+    use std::collections::HashMap;
+
+    fn caller(chat_id: &str, user_id: u64) -> Caller {
+        Caller {
+            chat_id: chat_id.to_string(),
+            user_id: Some(user_id),
+        }
+    }
+
+    #[test]
+    fn test_check_quota() {
+        let ledger_path = Path::new("test-quota-ledger.jsonl");
+        let overrides_path = Path::new("test-quota-overrides.json");
+        let ledger = UsageLedger::new(ledger_path);
+        let overrides = QuotaOverrides::new(overrides_path);
+        let usage = TokenUsage {
+            prompt_tokens: 600,
+            completion_tokens: 400,
+        };
+        ledger
+            .record(&UsageRecord::chat(
+                &caller("1", 10),
+                "gpt-4o",
+                usage,
+                &HashMap::new(),
+            ))
+            .unwrap();
+
+        let quotas = QuotaConfig {
+            user: QuotaLimits {
+                daily: Budget {
+                    tokens: Some(1000),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let now = Utc::now();
+
+        // Someone else in the same chat still has their budget
+        check_quota(&caller("1", 20), &quotas, &ledger, &overrides, now).unwrap();
+
+        let error = check_quota(&caller("2", 10), &quotas, &ledger, &overrides, now)
+            .unwrap_err()
+            .downcast::<QuotaExceeded>()
+            .unwrap();
+        assert_eq!(error.scope, QuotaScope::User);
+        assert_eq!(error.period, Period::Daily);
+        assert_eq!(error.resets_at, day_start(now) + Duration::days(1));
+
+        overrides
+            .grant(OverrideTarget::User(10), now + Duration::hours(1))
+            .unwrap();
+        check_quota(&caller("2", 10), &quotas, &ledger, &overrides, now).unwrap();
+        assert!(check_quota(
+            &caller("2", 10),
+            &quotas,
+            &ledger,
+            &overrides,
+            now + Duration::hours(2)
+        )
+        .is_err());
+
+        for file in ledger.files_since(DateTime::<Utc>::UNIX_EPOCH).unwrap() {
+            fs::remove_file(file).unwrap();
+        }
+        fs::remove_file(overrides_path).unwrap();
+    }
+
+    #[test]
+    fn test_monthly_reset() {
+        let now = Utc.with_ymd_and_hms(2025, 12, 31, 18, 30, 0).unwrap();
+        assert_eq!(
+            Period::Monthly.reset(now),
+            Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            Period::Daily.reset(now),
+            Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_grant() {
+        assert_eq!(
+            parse_grant("chat 3", "-100").unwrap(),
+            (OverrideTarget::Chat("-100".to_string()), Duration::hours(3))
+        );
+        assert_eq!(
+            parse_grant("42", "-100").unwrap(),
+            (OverrideTarget::User(42), Duration::hours(24))
+        );
+        assert!(parse_grant("", "-100").is_err());
+        assert!(parse_grant("someone", "-100").is_err());
+        assert!(parse_grant("42 -1", "-100").is_err());
+        assert!(parse_grant("chat 99999999999999", "-100").is_err());
+        assert!(parse_grant(&format!("chat {MAX_GRANT_HOURS}"), "-100").is_ok());
+    }
+}
@@ -0,0 +1,284 @@
+use super::config_manager::{BucketSpec, CommandLimit};
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// Full buckets are forgotten once this many are kept, a full bucket is the same as a new one
+const MAX_BUCKETS: usize = 10_000;
+
+/// Source of the current time, replaceable in tests
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real monotonic clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Whose bucket a use is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LimitScope {
+    User(u64),
+    Chat(i64),
+}
+
+/// A command was used too often
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cooldown {
+    pub scope: LimitScope,
+    /// Time until the next use is allowed
+    pub wait: Duration,
+}
+
+impl Cooldown {
+    /// A message for the chat that says when to try again
+    #[must_use]
+    pub fn user_message(&self, command: &str) -> String {
+        let seconds = self.wait.as_secs_f64().ceil().max(1.0);
+        match self.scope {
+            LimitScope::User(_) => {
+                format!("Slow down! You can use /{command} again in {seconds} seconds.")
+            }
+            LimitScope::Chat(_) => {
+                format!("This chat is using /{command} too often, try again in {seconds} seconds.")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // The spec of the last use, the config can change between uses
+    spec: BucketSpec,
+}
+
+impl Bucket {
+    /// Add the uses that were refilled since the last update
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let refilled = if self.spec.refill_seconds > 0.0 {
+            elapsed / self.spec.refill_seconds
+        } else {
+            f64::INFINITY
+        };
+        self.tokens = (self.tokens + refilled).min(f64::from(self.spec.capacity));
+        self.updated = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= f64::from(self.spec.capacity)
+    }
+
+    /// How long until a whole use is available
+    fn wait(&self) -> Duration {
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::try_from_secs_f64((1.0 - self.tokens) * self.spec.refill_seconds)
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+/// Token bucket rate limiter keyed by command and user or chat
+pub struct RateLimiter<C: Clock> {
+    clock: C,
+    buckets: Mutex<HashMap<(String, LimitScope), Bucket>>,
+}
+
+impl<C: Clock> RateLimiter<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Take one use of `command` from the buckets of the user and the chat.
+    /// Nothing is taken unless every bucket has a use left.
+    /// # Errors
+    /// The `Cooldown` of the first empty bucket
+    pub fn check(
+        &self,
+        command: &str,
+        limit: &CommandLimit,
+        user_id: Option<u64>,
+        chat_id: i64,
+    ) -> Result<(), Cooldown> {
+        let now = self.clock.now();
+        let mut scopes = Vec::new();
+        if let (Some(spec), Some(user_id)) = (limit.user, user_id) {
+            scopes.push((LimitScope::User(user_id), spec));
+        }
+        if let Some(spec) = limit.chat {
+            scopes.push((LimitScope::Chat(chat_id), spec));
+        }
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        for (scope, spec) in &scopes {
+            let bucket = buckets
+                .entry((command.to_string(), *scope))
+                .or_insert(Bucket {
+                    tokens: f64::from(spec.capacity),
+                    updated: now,
+                    spec: *spec,
+                });
+            bucket.spec = *spec;
+            bucket.refill(now);
+            if bucket.tokens < 1.0 {
+                return Err(Cooldown {
+                    scope: *scope,
+                    wait: bucket.wait(),
+                });
+            }
+        }
+        for (scope, _) in &scopes {
+            if let Some(bucket) = buckets.get_mut(&(command.to_string(), *scope)) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                !bucket.is_full()
+            });
+        }
+        Ok(())
+    }
+}
+
+static LIMITER: LazyLock<RateLimiter<SystemClock>> =
+    LazyLock::new(|| RateLimiter::new(SystemClock));
+
+/// The limiter shared by every command handler
+#[must_use]
+pub fn limiter() -> &'static RateLimiter<SystemClock> {
+    &LIMITER
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockClock {
+        start: Instant,
+        offset: Mutex<Duration>,
+    }
+
+    impl MockClock {
+        fn new() -> Self {
+            Self {
+                start: Instant::now(),
+                offset: Mutex::default(),
+            }
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.offset.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for &MockClock {
+        fn now(&self) -> Instant {
+            self.start + *self.offset.lock().unwrap()
+        }
+    }
+
+    fn limit(user: Option<(u32, f64)>, chat: Option<(u32, f64)>) -> CommandLimit {
+        let spec = |(capacity, refill_seconds)| BucketSpec {
+            capacity,
+            refill_seconds,
+        };
+        CommandLimit {
+            user: user.map(spec),
+            chat: chat.map(spec),
+        }
+    }
+
+    #[test]
+    fn test_bucket_empties_and_refills() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(&clock);
+        let limit = limit(Some((2, 10.0)), None);
+
+        assert!(limiter.check("image", &limit, Some(1), -1).is_ok());
+        assert!(limiter.check("image", &limit, Some(1), -1).is_ok());
+        let cooldown = limiter.check("image", &limit, Some(1), -1).unwrap_err();
+        assert_eq!(cooldown.scope, LimitScope::User(1));
+        assert_eq!(cooldown.wait, Duration::from_secs(10));
+
+        // Other users and commands have buckets of their own
+        assert!(limiter.check("image", &limit, Some(2), -1).is_ok());
+        assert!(limiter.check("gamble", &limit, Some(1), -1).is_ok());
+
+        clock.advance(Duration::from_secs(4));
+        let cooldown = limiter.check("image", &limit, Some(1), -1).unwrap_err();
+        assert_eq!(cooldown.wait, Duration::from_secs(6));
+
+        clock.advance(Duration::from_secs(6));
+        assert!(limiter.check("image", &limit, Some(1), -1).is_ok());
+        assert!(limiter.check("image", &limit, Some(1), -1).is_err());
+
+        // Refills stop at the capacity
+        clock.advance(Duration::from_secs(100));
+        assert!(limiter.check("image", &limit, Some(1), -1).is_ok());
+        assert!(limiter.check("image", &limit, Some(1), -1).is_ok());
+        assert!(limiter.check("image", &limit, Some(1), -1).is_err());
+    }
+
+    #[test]
+    fn test_chat_bucket_is_shared() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(&clock);
+        let limit = limit(Some((2, 60.0)), Some((3, 60.0)));
+
+        assert!(limiter.check("image", &limit, Some(1), -1).is_ok());
+        assert!(limiter.check("image", &limit, Some(2), -1).is_ok());
+        assert!(limiter.check("image", &limit, Some(3), -1).is_ok());
+        let cooldown = limiter.check("image", &limit, Some(4), -1).unwrap_err();
+        assert_eq!(cooldown.scope, LimitScope::Chat(-1));
+
+        // A refused use does not drain the other buckets
+        assert!(limiter.check("image", &limit, Some(1), -2).is_ok());
+        let cooldown = limiter.check("image", &limit, Some(1), -2).unwrap_err();
+        assert_eq!(cooldown.scope, LimitScope::User(1));
+        assert!(limiter.check("image", &limit, Some(5), -2).is_ok());
+    }
+
+    #[test]
+    fn test_only_full_buckets_are_forgotten() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(&clock);
+        let image = limit(Some((2, 600.0)), None);
+        let gamble = limit(Some((1, 1.0)), None);
+
+        // Half used, it must not come back full when the buckets are pruned
+        assert!(limiter.check("image", &image, Some(0), -1).is_ok());
+        for user_id in 1..=MAX_BUCKETS as u64 {
+            assert!(limiter.check("gamble", &gamble, Some(user_id), -1).is_ok());
+        }
+        clock.advance(Duration::from_secs(2));
+        assert!(limiter.check("gamble", &gamble, Some(1), -1).is_ok());
+        assert!(limiter.buckets.lock().unwrap().len() < 10);
+
+        assert!(limiter.check("image", &image, Some(0), -1).is_ok());
+        assert!(limiter.check("image", &image, Some(0), -1).is_err());
+    }
+
+    #[test]
+    fn test_cooldown_message() {
+        let cooldown = Cooldown {
+            scope: LimitScope::User(1),
+            wait: Duration::from_millis(1200),
+        };
+        assert!(cooldown.user_message("image").contains("in 2 seconds"));
+    }
+}
@@ -1,18 +1,110 @@
+use super::access::{check_access, permission_of, Access};
+use super::api_error::ApiError;
+use super::chat_history::{ChatHistory, Exchange, ImageRef, Role};
+use super::chat_settings::{ChatSettings, ChatSettingsStore};
+use super::config_manager::{ConfigManager, OutputConfig, Permission};
+use super::conversation::{addressed_prompt, Addressing};
+use super::documents::{read_document, DocumentStore, DocumentText};
+use super::message_split::{split_message, TELEGRAM_MESSAGE_LIMIT};
+use super::named_conversations::{self, MAIN};
 use super::open_ai_api::OpenAiApi;
+use super::personas::{self, PersonaCommand};
+use super::quota::{parse_grant, QuotaExceeded, QuotaOverrides};
+use super::rate_limit::limiter;
+use super::telegram_file::download;
+use super::telegram_format::{escape_html, markdown_to_html};
+use super::threads::{branch_key, drop_branches, resolve_thread, ThreadEntry, ThreadIndex};
+use super::usage::{day_start, month_start, Caller, UsageLedger, UsageScope, UsageTotals};
+use super::vision::download_photo;
+use chrono::Utc;
+use log::{debug, error, info, warn};
 use rand::Rng;
+use std::path::Path;
+use std::time::Duration;
 use teloxide::prelude::*;
-use teloxide::types::{DiceEmoji, InputFile};
+use teloxide::types::{
+    DiceEmoji, Document, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, Me, MessageId,
+    ParseMode, ReplyParameters,
+};
+use teloxide::{ApiError as ApiErrorKind, RequestError};
+use tokio::sync::watch;
+use tokio::time::{self, MissedTickBehavior};
 use url::Url;
 
 // How many games can someone request at a time
 const GAMBLE_MAX: u32 = 10;
 
+// Replaces an answer whose turn was taken out of the history
+const FORGOTTEN_ANSWER: &str = "🗑 This answer was forgotten.";
+
+const NOT_ASKER: &str = "Only the person who asked or an admin can change this answer.";
+
+// Shown while waiting for the first streamed tokens
+const STREAM_PLACEHOLDER: &str = "...";
+
 pub struct Response {
     pub bot: Bot,
     pub msg: Message,
 }
 
 impl Response {
+    /// API interface that accounts usage to this chat and the sender of the message
+    fn open_ai(&self) -> OpenAiApi {
+        self.open_ai_for(self.msg.from.as_ref().map(|user| user.id.0))
+    }
+
+    /// API interface that accounts usage to this chat and `user_id`
+    fn open_ai_for(&self, user_id: Option<u64>) -> OpenAiApi {
+        OpenAiApi::new().with_caller(Caller {
+            chat_id: self.msg.chat.id.to_string(),
+            user_id,
+        })
+    }
+
+    /// Check the sender may use `command` here, telling them when they may not
+    /// # Errors
+    /// Telegram API failure
+    pub async fn authorize(&self, command: &str) -> ResponseResult<bool> {
+        // Without the config the access lists are unknown, nobody gets in
+        let config = match ConfigManager::new() {
+            Ok(config) => config,
+            Err(error) => {
+                error!("Ignoring /{command}, the config file cannot be read: {error}");
+                return Ok(false);
+            }
+        };
+        let user_id = self.msg.from.as_ref().map(|user| user.id.0);
+        match check_access(&config.access, command, user_id, self.msg.chat.id.0) {
+            Access::Granted => Ok(true),
+            Access::Ignored => Ok(false),
+            Access::Denied(reason) => {
+                self.bot.send_message(self.msg.chat.id, reason).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Take a use of `command` from the rate limits, telling the sender when to come back
+    /// # Errors
+    /// Telegram API failure
+    pub async fn throttle(&self, command: &str) -> ResponseResult<bool> {
+        let config = ConfigManager::new().unwrap_or_default();
+        let Some(limit) = config.rate_limits.commands.get(command) else {
+            return Ok(true);
+        };
+
+        let user_id = self.msg.from.as_ref().map(|user| user.id.0);
+        match limiter().check(command, limit, user_id, self.msg.chat.id.0) {
+            Ok(()) => Ok(true),
+            Err(cooldown) => {
+                self.bot
+                    .send_message(self.msg.chat.id, cooldown.user_message(command))
+                    .await?;
+                Ok(false)
+            }
+        }
+    }
+
     /// Send help message with the given text
     /// # Errors
     /// Telegram API failure
@@ -38,10 +130,10 @@ impl Response {
     /// # Errors
     /// Telegram API failure
     pub async fn test_api(&self) -> ResponseResult<()> {
-        let open_ai = OpenAiApi::new();
+        let open_ai = self.open_ai();
         let response = match open_ai.test_connection().await {
             Ok(resp_string) => resp_string,
-            Err(error) => format!("Error during API setup: {error}"),
+            Err(error) => describe_error("Error during API setup", &error),
         };
         self.bot.send_message(self.msg.chat.id, response).await?;
         Ok(())
@@ -51,29 +143,1325 @@ impl Response {
     /// # Errors
     /// Telegram API failure
     pub async fn chat(&self, prompt: String) -> ResponseResult<()> {
-        let open_ai = OpenAiApi::new();
+        let config = ConfigManager::new().unwrap_or_default();
+        let prompt = match self.msg.document() {
+            Some(document) => {
+                let Some(text) = self.attached_document(document, &config).await? else {
+                    return Ok(());
+                };
+                if prompt.is_empty() {
+                    return self.keep_document(&text, &config).await;
+                }
+                let context = text.as_context(config.documents.context_chars);
+                format!("{context}\n\n{prompt}")
+            }
+            None => prompt,
+        };
+        let images = self.attached_images(&config).await;
+        let prompt = if prompt.is_empty() && !images.is_empty() {
+            config.vision.default_prompt.clone()
+        } else {
+            prompt
+        };
+        if prompt.is_empty() {
+            self.bot
+                .send_message(
+                    self.msg.chat.id,
+                    "Prompt is empty, usage: '/chat [PROMPT HERE]'",
+                )
+                .await?;
+            return Ok(());
+        }
+
+        let history_key = self.history_key(&config);
+        if config.streaming.enabled {
+            return self
+                .chat_streamed(prompt, history_key, images, &config)
+                .await;
+        }
+
+        let open_ai = self.open_ai().with_images(images);
+
+        let (response, answered) = match open_ai.chat(prompt, history_key.clone()).await {
+            Ok(answer) => (answer.text, Some(answer.exchange)),
+            Err(error) => (describe_error("Error during API call", &error), None),
+        };
+
+        let sent = self.send_long(&response, &config.output).await?;
+        if let Some(exchange) = answered {
+            self.remember_thread(&history_key, exchange.as_ref(), &sent);
+            self.offer_actions(&sent, &response, &config).await;
+            if self.voice_replies_enabled(&config) {
+                self.send_speech(&response).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer a prompt with a voice note, in writing if it cannot be read out
+    /// # Errors
+    /// Telegram API failure
+    pub async fn say(&self, prompt: String) -> ResponseResult<()> {
+        if prompt.is_empty() {
+            self.bot
+                .send_message(
+                    self.msg.chat.id,
+                    "Prompt is empty, usage: '/say [PROMPT HERE]'",
+                )
+                .await?;
+            return Ok(());
+        }
+
+        let config = ConfigManager::new().unwrap_or_default();
+        let history_key = self.history_key(&config);
+        let (response, exchange) = match self.open_ai().chat(prompt, history_key.clone()).await {
+            Ok(answer) => (answer.text, answer.exchange),
+            Err(error) => {
+                let response = describe_error("Error during API call", &error);
+                self.bot.send_message(self.msg.chat.id, response).await?;
+                return Ok(());
+            }
+        };
+
+        let sent = match self.send_speech(&response).await? {
+            Some(voice) => vec![voice.id],
+            None => self.send_long(&response, &config.output).await?,
+        };
+        self.remember_thread(&history_key, exchange.as_ref(), &sent);
+        Ok(())
+    }
+
+    fn voice_replies_enabled(&self, config: &ConfigManager) -> bool {
+        ChatSettingsStore::open()
+            .and_then(|store| store.get(&self.msg.chat.id.to_string()))
+            .unwrap_or_else(|error| {
+                warn!("Failed to read chat settings: {error}");
+                ChatSettings::default()
+            })
+            .voice_replies
+            .unwrap_or(config.voice.voice_replies)
+    }
+
+    /// Send `text` read out as a voice note. Failing to read it out is logged and nothing
+    /// is sent, the answer is in writing already or is sent that way instead.
+    async fn send_speech(&self, text: &str) -> ResponseResult<Option<Message>> {
+        let audio = match self.open_ai().speak(text).await {
+            Ok(audio) => audio,
+            Err(error) => {
+                warn!("Failed to read out the answer: {error}");
+                return Ok(None);
+            }
+        };
+        let voice = InputFile::memory(audio).file_name("answer.ogg");
+        Ok(Some(self.bot.send_voice(self.msg.chat.id, voice).await?))
+    }
+
+    /// The photo of the message, downloaded for the chat model. A failed download is
+    /// logged and the prompt is sent without it.
+    async fn attached_images(&self, config: &ConfigManager) -> Vec<ImageRef> {
+        let Some(sizes) = self.msg.photo() else {
+            return Vec::new();
+        };
+        match download_photo(&self.bot, sizes, &config.vision).await {
+            Ok(image) => image.into_iter().collect(),
+            Err(error) => {
+                warn!("Failed to download photo: {error}");
+                Vec::new()
+            }
+        }
+    }
+
+    /// Key of the history of the conversation this chat has active, see `/switch`
+    fn active_history(&self) -> String {
+        let chat_id = self.msg.chat.id.to_string();
+        ChatSettingsStore::open()
+            .and_then(|store| named_conversations::active_key(&store, &chat_id))
+            .unwrap_or_else(|error| {
+                warn!("Failed to read the active conversation, using the main one: {error}");
+                chat_id
+            })
+    }
+
+    /// Key of the history a chat message continues, see `resolve_thread`
+    fn history_key(&self, config: &ConfigManager) -> String {
+        let active = self.active_history();
+        if !config.conversation.threads {
+            return active;
+        }
+
+        let chat_id = self.msg.chat.id.to_string();
+        let reply_to = self.msg.reply_to_message().map(|reply| reply.id.0);
+        ThreadIndex::open()
+            .and_then(|index| resolve_thread(&index, &chat_id, &active, reply_to, self.msg.id.0))
+            .unwrap_or_else(|error| {
+                warn!("Failed to resolve reply thread, using the chat history: {error}");
+                active
+            })
+    }
+
+    /// Key of the history a bot message was answered from, the active history if it is
+    /// unknown. Unlike `history_key` this never starts a new branch.
+    fn thread_of(&self, message_id: MessageId) -> String {
+        self.thread_entry(message_id.0)
+            .map_or_else(|| self.active_history(), |entry| entry.history_key)
+    }
+
+    /// What the index knows about a bot message, `None` if it is unknown
+    fn thread_entry(&self, message_id: i32) -> Option<ThreadEntry> {
+        ThreadIndex::open()
+            .and_then(|index| index.lookup(&self.msg.chat.id.to_string(), message_id))
+            .unwrap_or_else(|error| {
+                warn!("Failed to look up the thread of an answer: {error}");
+                None
+            })
+    }
+
+    /// Whether `user_id` may undo or redo the answer of `entry`, only the user who asked
+    /// and admins can
+    fn may_change(
+        &self,
+        config: &ConfigManager,
+        user_id: Option<u64>,
+        entry: Option<&ThreadEntry>,
+    ) -> bool {
+        // Answers from before askers were kept can only be told apart in private chats
+        let is_asker = entry
+            .and_then(|entry| entry.asker)
+            .map_or(self.msg.chat.is_private(), |asker| Some(asker) == user_id);
+        is_asker
+            || permission_of(&config.access, user_id, self.msg.chat.id.0) == Some(Permission::Admin)
+    }
+
+    /// The history the command applies to, the thread of the answer it replies to if any
+    fn command_history(&self) -> String {
+        match self.msg.reply_to_message() {
+            Some(reply) => self.thread_of(reply.id),
+            None => self.active_history(),
+        }
+    }
+
+    /// Delete the reply threads of a history that started over, a failure leaves them behind
+    fn drop_branches(&self, history_key: &str) {
+        let result = ThreadIndex::open()
+            .and_then(|index| drop_branches(&index, &self.msg.chat.id.to_string(), history_key));
+        if let Err(error) = result {
+            warn!("Failed to delete the reply threads of {history_key}: {error}");
+        }
+    }
+
+    /// Record which messages hold the exchange and who asked, so replies to them continue
+    /// `history_key`
+    fn remember_thread(&self, history_key: &str, exchange: Option<&Exchange>, sent: &[MessageId]) {
+        let (Some(exchange), Some(first)) = (exchange, sent.first()) else {
+            return;
+        };
+        // Kept with threads turned off too, the answer buttons need to know who asked
+        let result = ChatHistory::tag_exchange(history_key, exchange, self.msg.id.0, first.0)
+            .and_then(|()| {
+                let entry = ThreadEntry {
+                    history_key: history_key.to_string(),
+                    anchor: first.0,
+                    asker: self.msg.from.as_ref().map(|user| user.id.0),
+                };
+                let ids: Vec<i32> = sent.iter().map(|id| id.0).collect();
+                ThreadIndex::open()?.remember(&self.msg.chat.id.to_string(), &ids, &entry)
+            });
+        if let Err(error) = result {
+            warn!("Failed to remember reply thread: {error}");
+        }
+    }
+
+    /// Put the action buttons under an answer that fits in one message, a failure only costs
+    /// the buttons
+    async fn offer_actions(&self, sent: &[MessageId], answer: &str, config: &ConfigManager) {
+        let [message_id] = sent else {
+            return;
+        };
+        if !config.output.answer_buttons || split_message(answer, TELEGRAM_MESSAGE_LIMIT).len() != 1
+        {
+            return;
+        }
+        let result = self
+            .bot
+            .edit_message_reply_markup(self.msg.chat.id, *message_id)
+            .reply_markup(answer_keyboard())
+            .await;
+        if let Err(error) = result {
+            warn!("Failed to add the answer buttons: {error}");
+        }
+    }
+
+    /// Run the action of a button under an answer, `self.msg` is the answer
+    /// # Errors
+    /// Telegram API failure
+    pub async fn answer_action(&self, query: &CallbackQuery) -> ResponseResult<()> {
+        let action = query.data.as_deref().and_then(AnswerAction::parse);
+        let notice = match action {
+            Some(action) => self.run_answer_action(action, query).await?,
+            None => Some("This button is no longer supported.".to_string()),
+        };
+
+        let mut reply = self.bot.answer_callback_query(query.id.clone());
+        if let Some(notice) = notice {
+            reply = reply.text(notice);
+        }
+        reply.await?;
+        Ok(())
+    }
+
+    /// Returns a notice for the user who pressed the button, if there is one
+    async fn run_answer_action(
+        &self,
+        action: AnswerAction,
+        query: &CallbackQuery,
+    ) -> ResponseResult<Option<String>> {
+        let config = match ConfigManager::new() {
+            Ok(config) => config,
+            Err(error) => {
+                error!("Ignoring an answer button, the config file cannot be read: {error}");
+                return Ok(None);
+            }
+        };
+        let user_id = query.from.id.0;
+        let chat_id = self.msg.chat.id;
+        match check_access(&config.access, "chat", Some(user_id), chat_id.0) {
+            Access::Granted => {}
+            Access::Ignored => return Ok(None),
+            Access::Denied(reason) => return Ok(Some(reason)),
+        }
+        let entry = self.thread_entry(self.msg.id.0);
+        if !self.may_change(&config, Some(user_id), entry.as_ref()) {
+            return Ok(Some(NOT_ASKER.to_string()));
+        }
+
+        // Messages added to the answer belong to the same thread
+        let entry = entry.unwrap_or_else(|| ThreadEntry {
+            history_key: self.active_history(),
+            anchor: self.msg.id.0,
+            asker: None,
+        });
+        let history_key = entry.history_key.clone();
+        let is_latest = ChatHistory::new(&history_key).is_ok_and(|history| {
+            history
+                .messages
+                .last()
+                .is_some_and(|m| m.role == "assistant" && m.message_id == Some(self.msg.id.0))
+        });
+        if !is_latest {
+            return Ok(Some(
+                "Only the latest answer of a conversation can be changed.".to_string(),
+            ));
+        }
+
+        // Forgetting costs nothing, the other actions ask the model again
+        if action != AnswerAction::Forget {
+            if let Some(limit) = config.rate_limits.commands.get("chat") {
+                if let Err(cooldown) = limiter().check("chat", limit, Some(user_id), chat_id.0) {
+                    return Ok(Some(cooldown.user_message("chat")));
+                }
+            }
+        }
+
+        let open_ai = self.open_ai_for(Some(user_id));
+        match action {
+            AnswerAction::Regenerate => {
+                let answer = match open_ai.regenerate(history_key.clone()).await {
+                    Ok(answer) => answer,
+                    Err(error) => return Ok(Some(describe_error("Error during API call", &error))),
+                };
+                // The question is still tagged, only the new answer needs it
+                if let Some(exchange) = &answer.exchange {
+                    if let Err(error) =
+                        ChatHistory::tag_exchange(&history_key, exchange, 0, self.msg.id.0)
+                    {
+                        warn!("Failed to tag the new answer: {error}");
+                    }
+                }
+                self.replace_answer(&answer.text, &entry, &config).await?;
+                Ok(None)
+            }
+            AnswerAction::Continue => {
+                let continuation = match open_ai.continue_answer(history_key.clone()).await {
+                    Ok(continuation) => continuation,
+                    Err(error) => return Ok(Some(describe_error("Error during API call", &error))),
+                };
+                let answer = ChatHistory::new(&history_key)
+                    .ok()
+                    .and_then(|history| history.messages.last().map(|m| m.content.clone()))
+                    .unwrap_or_default();
+                if split_message(&answer, TELEGRAM_MESSAGE_LIMIT).len() == 1 {
+                    self.edit_formatted(self.msg.id, &answer, Some(answer_keyboard()))
+                        .await?;
+                } else {
+                    // Too long to edit in, the rest follows in new messages
+                    self.bot
+                        .edit_message_reply_markup(chat_id, self.msg.id)
+                        .await?;
+                    let sent = self.send_long(&continuation, &config.output).await?;
+                    self.remember_parts(&entry, &sent);
+                }
+                Ok(None)
+            }
+            AnswerAction::Forget => {
+                if let Err(error) = ChatHistory::forget_turn(&history_key) {
+                    return Ok(Some(format!(
+                        "Error while changing the chat history: {error}"
+                    )));
+                }
+                self.bot
+                    .edit_message_text(chat_id, self.msg.id, FORGOTTEN_ANSWER)
+                    .await?;
+                Ok(Some(
+                    "Forgotten, I will answer as if it was never asked.".to_string(),
+                ))
+            }
+        }
+    }
+
+    /// Show a new answer in place of the one with the buttons
+    async fn replace_answer(
+        &self,
+        answer: &str,
+        entry: &ThreadEntry,
+        config: &ConfigManager,
+    ) -> ResponseResult<()> {
+        let mut parts = split_message(answer, TELEGRAM_MESSAGE_LIMIT).into_iter();
+        let first = parts.next().unwrap_or_default();
+        if parts.len() == 0 {
+            return self
+                .edit_formatted(self.msg.id, &first, Some(answer_keyboard()))
+                .await;
+        }
+
+        self.edit_formatted(self.msg.id, &first, None).await?;
+        let rest: Vec<String> = parts.collect();
+        let sent = self.send_long(&rest.join("\n\n"), &config.output).await?;
+        self.remember_parts(entry, &sent);
+        Ok(())
+    }
+
+    /// Record the messages an answer went on in as part of its thread
+    fn remember_parts(&self, entry: &ThreadEntry, sent: &[MessageId]) {
+        let ids: Vec<i32> = sent.iter().map(|id| id.0).collect();
+        let result = ThreadIndex::open()
+            .and_then(|index| index.remember(&self.msg.chat.id.to_string(), &ids, entry));
+        if let Err(error) = result {
+            warn!("Failed to remember reply thread: {error}");
+        }
+    }
+
+    /// Send a reply that may be longer than one message, in parts or as a document
+    async fn send_long(&self, text: &str, output: &OutputConfig) -> ResponseResult<Vec<MessageId>> {
+        let parts = split_message(text, TELEGRAM_MESSAGE_LIMIT);
+        if output.max_parts > 0 && parts.len() > output.max_parts {
+            let document = self.send_as_document(text, output).await?;
+            return Ok(vec![document.id]);
+        }
+        let mut sent = Vec::new();
+        for part in parts {
+            sent.push(self.send_formatted(&part).await?.id);
+        }
+        Ok(sent)
+    }
+
+    /// Send Markdown as Telegram HTML, or as it is when Telegram cannot parse the result
+    async fn send_formatted(&self, markdown: &str) -> ResponseResult<Message> {
+        let sent = self
+            .bot
+            .send_message(self.msg.chat.id, markdown_to_html(markdown))
+            .parse_mode(ParseMode::Html)
+            .await;
+        match sent {
+            Err(RequestError::Api(ApiErrorKind::CantParseEntities(error))) => {
+                warn!("Sending reply as plaintext, Telegram rejected its formatting: {error}");
+                self.bot.send_message(self.msg.chat.id, markdown).await
+            }
+            result => result,
+        }
+    }
+
+    /// Replace the text of a message with Markdown as Telegram HTML, or as it is when
+    /// Telegram cannot parse the result
+    async fn edit_formatted(
+        &self,
+        message_id: MessageId,
+        markdown: &str,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> ResponseResult<()> {
+        let mut edit = self
+            .bot
+            .edit_message_text(self.msg.chat.id, message_id, markdown_to_html(markdown))
+            .parse_mode(ParseMode::Html);
+        let mut plain = self
+            .bot
+            .edit_message_text(self.msg.chat.id, message_id, markdown);
+        if let Some(keyboard) = keyboard {
+            edit = edit.reply_markup(keyboard.clone());
+            plain = plain.reply_markup(keyboard);
+        }
+        match edit.await {
+            Err(RequestError::Api(ApiErrorKind::CantParseEntities(error))) => {
+                warn!("Sending reply as plaintext, Telegram rejected its formatting: {error}");
+                plain.await?;
+            }
+            result => {
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_as_document(&self, text: &str, output: &OutputConfig) -> ResponseResult<Message> {
+        let file = InputFile::memory(text.to_string().into_bytes())
+            .file_name(format!("answer.{}", output.document_extension));
+        self.bot
+            .send_document(self.msg.chat.id, file)
+            .caption("The answer is too long for a message, here it is as a file.")
+            .await
+    }
+
+    /// Send a placeholder message and keep editing it while the answer streams in
+    async fn chat_streamed(
+        &self,
+        prompt: String,
+        history_key: String,
+        images: Vec<ImageRef>,
+        config: &ConfigManager,
+    ) -> ResponseResult<()> {
+        let open_ai = self.open_ai().with_images(images);
+
+        let placeholder = self
+            .bot
+            .send_message(self.msg.chat.id, STREAM_PLACEHOLDER)
+            .await?;
+
+        let (progress, mut updates) = watch::channel(String::new());
+        let request = open_ai.chat_stream(prompt, history_key.clone(), progress);
+        tokio::pin!(request);
+
+        let mut ticker = time::interval(Duration::from_millis(config.streaming.edit_interval_ms));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut shown = String::new();
+        let result = loop {
+            tokio::select! {
+                result = &mut request => break result,
+                _ = ticker.tick() => {
+                    if !updates.has_changed().unwrap_or(false) {
+                        continue;
+                    }
+                    // Only the first message worth of a long answer is shown while it streams
+                    let partial = split_message(&updates.borrow_and_update(), TELEGRAM_MESSAGE_LIMIT)
+                        .into_iter()
+                        .next()
+                        .unwrap_or_default();
+                    // A failed intermediate edit is not fatal, the final edit will catch up
+                    match self
+                        .bot
+                        .edit_message_text(self.msg.chat.id, placeholder.id, &partial)
+                        .await
+                    {
+                        Ok(_) => shown = partial,
+                        Err(error) => warn!("Failed to edit streamed reply: {error}"),
+                    }
+                }
+            }
+        };
+
+        let (response, answered) = match result {
+            Ok(answer) => (answer.text, Some(answer.exchange)),
+            Err(error) => (describe_error("Error during API call", &error), None),
+        };
+
+        let mut parts = split_message(&response, TELEGRAM_MESSAGE_LIMIT).into_iter();
+        let output = &config.output;
+        let sent = if output.max_parts > 0 && parts.len() > output.max_parts {
+            // A placeholder left behind is not fatal, the answer still has to be sent
+            if let Err(error) = self
+                .bot
+                .delete_message(self.msg.chat.id, placeholder.id)
+                .await
+            {
+                warn!("Failed to delete the streamed reply: {error}");
+            }
+            vec![self.send_as_document(&response, output).await?.id]
+        } else {
+            // The streamed text was shown without formatting, it only stays if it looks the same
+            let first = parts.next().unwrap_or_else(|| response.clone());
+            if markdown_to_html(&first) != escape_html(&shown) {
+                self.edit_formatted(placeholder.id, &first, None).await?;
+            }
+            let mut sent = vec![placeholder.id];
+            for part in parts {
+                sent.push(self.send_formatted(&part).await?.id);
+            }
+            sent
+        };
+
+        if let Some(exchange) = answered {
+            self.remember_thread(&history_key, exchange.as_ref(), &sent);
+            self.offer_actions(&sent, &response, config).await;
+            if self.voice_replies_enabled(config) {
+                self.send_speech(&response).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer a message without a command if it is meant for the bot
+    /// # Errors
+    /// Telegram API failure
+    pub async fn converse(&self, me: &Me) -> ResponseResult<()> {
+        let replies_to_bot = self
+            .msg
+            .reply_to_message()
+            .and_then(|reply| reply.from.as_ref())
+            .is_some_and(|user| user.id == me.id);
+        let addressing = Addressing {
+            is_private: self.msg.chat.is_private(),
+            replies_to_bot,
+            bot_username: me.username(),
+        };
+        if self.msg.voice().is_some() || self.msg.audio().is_some() {
+            return self.voice_message(addressing).await;
+        }
+        if self.msg.document().is_some() {
+            return self.document_message(addressing).await;
+        }
 
-        let chat_id = format!("{}", self.msg.chat.id);
+        // Photos carry their text in the caption
+        let Some(text) = self
+            .msg
+            .text()
+            .or_else(|| self.msg.caption())
+            .or_else(|| self.msg.photo().map(|_| ""))
+        else {
+            return Ok(());
+        };
+        let Some(prompt) = addressed_prompt(text, addressing) else {
+            return Ok(());
+        };
+        if prompt.is_empty() && self.msg.photo().is_none() {
+            return Ok(());
+        }
+        if !self.auto_reply_enabled() {
+            return Ok(());
+        }
+
+        if !self.authorize("chat").await? || !self.throttle("chat").await? {
+            return Ok(());
+        }
+        self.chat(prompt).await
+    }
+
+    fn auto_reply_enabled(&self) -> bool {
+        let config = ConfigManager::new().unwrap_or_default();
+        let settings = ChatSettingsStore::open()
+            .and_then(|store| store.get(&self.msg.chat.id.to_string()))
+            .unwrap_or_else(|error| {
+                warn!("Failed to read chat settings: {error}");
+                ChatSettings::default()
+            });
+        settings
+            .auto_reply
+            .unwrap_or(config.conversation.auto_reply)
+    }
 
-        let response = match open_ai.chat(prompt, chat_id).await {
+    /// Transcribe a voice or audio message. The transcript is answered like a text message
+    /// when the message is meant for the bot, other voice messages in groups only get the
+    /// transcript posted if that is turned on.
+    async fn voice_message(&self, addressing: Addressing<'_>) -> ResponseResult<()> {
+        let config = ConfigManager::new().unwrap_or_default();
+        let addressed = addressing.is_private
+            || addressing.replies_to_bot
+            || self
+                .msg
+                .caption()
+                .and_then(|caption| addressed_prompt(caption, addressing))
+                .is_some();
+        let chat = addressed && config.voice.chat_with_transcript && self.auto_reply_enabled();
+        let post = config.voice.post_transcript && (addressed || config.voice.transcribe_groups);
+        if !chat && !post {
+            return Ok(());
+        }
+
+        if !self.authorize("transcribe").await? || !self.throttle("transcribe").await? {
+            return Ok(());
+        }
+        let transcript = match self.transcribe().await {
+            Ok(transcript) => transcript,
+            Err(error) => {
+                self.bot
+                    .send_message(
+                        self.msg.chat.id,
+                        describe_error("Error during transcription", &error),
+                    )
+                    .reply_parameters(ReplyParameters::new(self.msg.id))
+                    .await?;
+                return Ok(());
+            }
+        };
+        if transcript.is_empty() {
+            return Ok(());
+        }
+
+        if post {
+            self.bot
+                .send_message(self.msg.chat.id, format!("🎙 {transcript}"))
+                .reply_parameters(ReplyParameters::new(self.msg.id))
+                .await?;
+        }
+        if chat && self.authorize("chat").await? && self.throttle("chat").await? {
+            self.chat(transcript).await?;
+        }
+        Ok(())
+    }
+
+    /// Read a document meant for the bot into the chat history, a caption is answered as a
+    /// question about it. Like other messages, a document in a group is only for the bot
+    /// when its caption mentions the bot or it replies to the bot, documents without a
+    /// caption are ignored otherwise.
+    async fn document_message(&self, addressing: Addressing<'_>) -> ResponseResult<()> {
+        let caption = self.msg.caption().unwrap_or_default();
+        let Some(prompt) = addressed_prompt(caption, addressing) else {
+            return Ok(());
+        };
+        if !self.auto_reply_enabled()
+            || !self.authorize("chat").await?
+            || !self.throttle("chat").await?
+        {
+            return Ok(());
+        }
+        self.chat(prompt).await
+    }
+
+    /// The text of a document sent with a prompt. A document that cannot be read is
+    /// answered with the error and `None` is returned.
+    async fn attached_document(
+        &self,
+        document: &Document,
+        config: &ConfigManager,
+    ) -> ResponseResult<Option<DocumentText>> {
+        match self.read_document(document, config).await {
+            Ok(text) => Ok(Some(text)),
+            Err(error) => {
+                self.bot
+                    .send_message(
+                        self.msg.chat.id,
+                        format!("Error while reading the document: {error}"),
+                    )
+                    .reply_parameters(ReplyParameters::new(self.msg.id))
+                    .await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Add a document sent without a question to the chat history for later questions
+    async fn keep_document(
+        &self,
+        text: &DocumentText,
+        config: &ConfigManager,
+    ) -> ResponseResult<()> {
+        let context = text.as_context(config.documents.context_chars);
+        let history_key = self.history_key(config);
+        let result = ChatHistory::new(&history_key)
+            .and_then(|history| history.add_entry(&history_key, &Role::User, &context));
+        let response = match result {
+            Ok(_) => format!(
+                "I have read {}, ask me about it or use /summarize.",
+                text.name
+            ),
+            Err(error) => format!("Error while saving the document: {error}"),
+        };
+        self.bot
+            .send_message(self.msg.chat.id, response)
+            .reply_parameters(ReplyParameters::new(self.msg.id))
+            .await?;
+        Ok(())
+    }
+
+    /// Download and read a document, and keep it as the last one of the chat for /summarize
+    async fn read_document(
+        &self,
+        document: &Document,
+        config: &ConfigManager,
+    ) -> anyhow::Result<DocumentText> {
+        let text = read_document(&self.bot, document, &config.documents).await?;
+        let store = DocumentStore::new(Path::new(&config.documents.dir));
+        if let Err(error) = store.save(&self.msg.chat.id.to_string(), &text) {
+            warn!("Failed to keep the document for /summarize: {error}");
+        }
+        Ok(text)
+    }
+
+    /// Summarize the document the command replies to, or else the last one read in the chat
+    /// # Errors
+    /// Telegram API failure
+    pub async fn summarize(&self) -> ResponseResult<()> {
+        let config = ConfigManager::new().unwrap_or_default();
+        let document = match self.msg.reply_to_message().and_then(Message::document) {
+            Some(document) => self.read_document(document, &config).await.map(Some),
+            None => DocumentStore::new(Path::new(&config.documents.dir))
+                .latest(&self.msg.chat.id.to_string()),
+        };
+
+        let response = match document {
+            Ok(Some(document)) => match self.open_ai().summarize_document(&document).await {
+                Ok(summary) => summary,
+                Err(error) => describe_error("Error during API call", &error),
+            },
+            Ok(None) => "Send me a document first, or reply to one with /summarize.".to_string(),
+            Err(error) => format!("Error while reading the document: {error}"),
+        };
+        self.send_long(&response, &config.output).await?;
+        Ok(())
+    }
+
+    async fn transcribe(&self) -> anyhow::Result<String> {
+        let (file_id, file_name, duration) = match (self.msg.voice(), self.msg.audio()) {
+            (Some(voice), _) => (&voice.file.id, "voice.ogg".to_string(), voice.duration),
+            (None, Some(audio)) => (
+                &audio.file.id,
+                audio
+                    .file_name
+                    .clone()
+                    .unwrap_or_else(|| "audio.mp3".to_string()),
+                audio.duration,
+            ),
+            (None, None) => return Ok(String::new()),
+        };
+        let audio = download(&self.bot, file_id).await?;
+        self.open_ai()
+            .transcribe(audio, &file_name, duration.seconds())
+            .await
+    }
+
+    /// Turn answering messages without /chat on or off for this chat
+    /// # Errors
+    /// Telegram API failure
+    pub async fn auto_reply(&self, prompt: String) -> ResponseResult<()> {
+        let response = match self.set_auto_reply(prompt.trim()) {
             Ok(resp_string) => resp_string,
-            Err(error) => format!("Error during API call: {error}"),
+            Err(error) => format!("Error while changing the chat settings: {error}"),
         };
 
         self.bot.send_message(self.msg.chat.id, response).await?;
         Ok(())
     }
 
+    fn set_auto_reply(&self, argument: &str) -> anyhow::Result<String> {
+        let store = ChatSettingsStore::open()?;
+        let chat_id = self.msg.chat.id.to_string();
+        let enabled = match argument {
+            "on" => true,
+            "off" => false,
+            "" => {
+                let config = ConfigManager::new()?;
+                let enabled = store
+                    .get(&chat_id)?
+                    .auto_reply
+                    .unwrap_or(config.conversation.auto_reply);
+                return Ok(format!(
+                    "Auto reply is {}, usage: '/autoreply [on|off]'",
+                    if enabled { "on" } else { "off" }
+                ));
+            }
+            _ => return Ok("Usage: '/autoreply [on|off]'".to_string()),
+        };
+
+        store.update(&chat_id, |settings| settings.auto_reply = Some(enabled))?;
+        Ok(if enabled {
+            "Auto reply is on, I will answer private messages, mentions and replies to me."
+                .to_string()
+        } else {
+            "Auto reply is off, use /chat to talk to me.".to_string()
+        })
+    }
+
+    /// Turn answering with voice notes as well on or off for this chat
+    /// # Errors
+    /// Telegram API failure
+    pub async fn voice_replies(&self, prompt: String) -> ResponseResult<()> {
+        let response = match self.set_voice_replies(prompt.trim()) {
+            Ok(resp_string) => resp_string,
+            Err(error) => format!("Error while changing the chat settings: {error}"),
+        };
+
+        self.bot.send_message(self.msg.chat.id, response).await?;
+        Ok(())
+    }
+
+    fn set_voice_replies(&self, argument: &str) -> anyhow::Result<String> {
+        let store = ChatSettingsStore::open()?;
+        let chat_id = self.msg.chat.id.to_string();
+        let enabled = match argument {
+            "on" => true,
+            "off" => false,
+            "" => {
+                let config = ConfigManager::new()?;
+                let enabled = store
+                    .get(&chat_id)?
+                    .voice_replies
+                    .unwrap_or(config.voice.voice_replies);
+                return Ok(format!(
+                    "Voice replies are {}, usage: '/voicereplies [on|off]'",
+                    if enabled { "on" } else { "off" }
+                ));
+            }
+            _ => return Ok("Usage: '/voicereplies [on|off]'".to_string()),
+        };
+
+        store.update(&chat_id, |settings| settings.voice_replies = Some(enabled))?;
+        Ok(if enabled {
+            "Voice replies are on, I will also read my answers out.".to_string()
+        } else {
+            "Voice replies are off.".to_string()
+        })
+    }
+
+    /// Drop the last question and its answer from the history
+    /// # Errors
+    /// Telegram API failure
+    pub async fn undo(&self) -> ResponseResult<()> {
+        let config = ConfigManager::new().unwrap_or_default();
+        let history_key = self.command_history();
+        let last_answer = ChatHistory::new(&history_key)
+            .ok()
+            .and_then(|history| history.messages.last().cloned())
+            .filter(|m| m.role == "assistant")
+            .and_then(|m| m.message_id);
+        let user_id = self.msg.from.as_ref().map(|user| user.id.0);
+        let entry = last_answer.and_then(|answer| self.thread_entry(answer));
+        if !self.may_change(&config, user_id, entry.as_ref()) {
+            self.bot.send_message(self.msg.chat.id, NOT_ASKER).await?;
+            return Ok(());
+        }
+
+        let response = match self.undo_turn(&history_key) {
+            Ok(Some(answers)) => {
+                for answer in answers {
+                    self.mark_forgotten(answer).await;
+                }
+                "Forgot the last question and its answer.".to_string()
+            }
+            Ok(None) => "There is nothing to undo.".to_string(),
+            Err(error) => format!("Error while changing the chat history: {error}"),
+        };
+
+        self.bot.send_message(self.msg.chat.id, response).await?;
+        Ok(())
+    }
+
+    /// The messages the forgotten answers were sent in, `None` without a question to forget
+    fn undo_turn(&self, history_key: &str) -> anyhow::Result<Option<Vec<MessageId>>> {
+        let removed = ChatHistory::forget_turn(history_key)?;
+        if removed.is_empty() {
+            return Ok(None);
+        }
+        let answers = removed
+            .iter()
+            .filter(|m| m.role == "assistant")
+            .filter_map(|m| m.message_id.map(MessageId))
+            .collect();
+        Ok(Some(answers))
+    }
+
+    async fn mark_forgotten(&self, answer: MessageId) {
+        let result = self
+            .bot
+            .edit_message_text(self.msg.chat.id, answer, FORGOTTEN_ANSWER)
+            .await;
+        if let Err(error) = result {
+            warn!("Failed to mark an answer as forgotten: {error}");
+        }
+    }
+
+    /// Answer the last question again with a fresh completion
+    /// # Errors
+    /// Telegram API failure
+    pub async fn retry(&self) -> ResponseResult<()> {
+        let config = ConfigManager::new().unwrap_or_default();
+        let history_key = self.command_history();
+        let previous = match ChatHistory::new(&history_key) {
+            Ok(history) if history.messages.iter().any(|m| m.role == "user") => history
+                .messages
+                .last()
+                .filter(|m| m.role == "assistant")
+                .and_then(|m| m.message_id),
+            Ok(_) => {
+                self.bot
+                    .send_message(self.msg.chat.id, "There is nothing to retry.")
+                    .await?;
+                return Ok(());
+            }
+            Err(error) => {
+                self.bot
+                    .send_message(
+                        self.msg.chat.id,
+                        format!("Error while reading the chat history: {error}"),
+                    )
+                    .await?;
+                return Ok(());
+            }
+        };
+        let user_id = self.msg.from.as_ref().map(|user| user.id.0);
+        let entry = previous.and_then(|answer| self.thread_entry(answer));
+        if !self.may_change(&config, user_id, entry.as_ref()) {
+            self.bot.send_message(self.msg.chat.id, NOT_ASKER).await?;
+            return Ok(());
+        }
+
+        let (response, answered) = match self.open_ai().regenerate(history_key.clone()).await {
+            Ok(answer) => (answer.text, Some(answer.exchange)),
+            Err(error) => (describe_error("Error during API call", &error), None),
+        };
+        let sent = self.send_long(&response, &config.output).await?;
+        if let Some(exchange) = answered {
+            // The buttons of the replaced answer no longer apply
+            if let Some(previous) = previous {
+                let result = self
+                    .bot
+                    .edit_message_reply_markup(self.msg.chat.id, MessageId(previous))
+                    .await;
+                if let Err(error) = result {
+                    debug!("Failed to take the buttons off the previous answer: {error}");
+                }
+            }
+            self.remember_thread(&history_key, exchange.as_ref(), &sent);
+            self.offer_actions(&sent, &response, &config).await;
+        }
+        Ok(())
+    }
+
+    /// Answer the edited text of a question that was answered before, in place of the old
+    /// answer. `command_prompt` is the prompt if the message is a /chat command.
+    /// Only the last question of a conversation is answered again.
+    /// # Errors
+    /// Telegram API failure
+    pub async fn edited(&self, command_prompt: Option<String>, me: &Me) -> ResponseResult<()> {
+        // The history only holds the caption of documents together with their text
+        if self.msg.document().is_some() {
+            return Ok(());
+        }
+        let prompt = match command_prompt {
+            Some(prompt) => prompt,
+            None => {
+                let text = self.msg.text().or_else(|| self.msg.caption()).unwrap_or("");
+                let addressing = Addressing {
+                    is_private: self.msg.chat.is_private(),
+                    replies_to_bot: self
+                        .msg
+                        .reply_to_message()
+                        .and_then(|reply| reply.from.as_ref())
+                        .is_some_and(|user| user.id == me.id),
+                    bot_username: me.username(),
+                };
+                let Some(prompt) = addressed_prompt(text, addressing) else {
+                    return Ok(());
+                };
+                prompt
+            }
+        };
+        if prompt.is_empty() {
+            return Ok(());
+        }
+        let Some((history_key, answer)) = self.edited_turn() else {
+            return Ok(());
+        };
+        if !self.authorize("chat").await? || !self.throttle("chat").await? {
+            return Ok(());
+        }
+
+        let config = ConfigManager::new().unwrap_or_default();
+        let rewritten = ChatHistory::rewrite_question(&history_key, self.msg.id.0, &prompt);
+        match rewritten {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(error) => {
+                warn!("Failed to rewrite an edited question: {error}");
+                return Ok(());
+            }
+        }
+
+        let (response, answered) = match self.open_ai().regenerate(history_key.clone()).await {
+            Ok(answer) => (answer.text, Some(answer.exchange)),
+            Err(error) => (describe_error("Error during API call", &error), None),
+        };
+        let fits = split_message(&response, TELEGRAM_MESSAGE_LIMIT).len() == 1;
+        let sent = match answer {
+            Some(answer) if fits => {
+                let buttons =
+                    (answered.is_some() && config.output.answer_buttons).then(answer_keyboard);
+                self.edit_formatted(answer, &response, buttons).await?;
+                vec![answer]
+            }
+            _ => self.send_long(&response, &config.output).await?,
+        };
+        if let Some(exchange) = answered {
+            self.remember_thread(&history_key, exchange.as_ref(), &sent);
+            if sent.first() != answer.as_ref() {
+                self.offer_actions(&sent, &response, &config).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// The history holding the question of this message and the message of its answer.
+    /// A reply that started a branch is in its own history, others in the thread they reply
+    /// to or the chat history.
+    fn edited_turn(&self) -> Option<(String, Option<MessageId>)> {
+        let chat_id = self.msg.chat.id.to_string();
+        let mut candidates = Vec::new();
+        if let Some(reply) = self.msg.reply_to_message() {
+            let thread = self.thread_of(reply.id);
+            candidates.push(branch_key(&thread, self.msg.id.0));
+            candidates.push(thread);
+        }
+        // Questions stay in the conversation they were asked in, even after a /switch
+        let active = self.active_history();
+        if active != chat_id {
+            candidates.push(active);
+        }
+        candidates.push(chat_id);
+
+        candidates.into_iter().find_map(|history_key| {
+            let history = ChatHistory::find(&history_key).ok()??;
+            let question = history.position_of(self.msg.id.0)?;
+            let answer = history.messages[question + 1..]
+                .iter()
+                .find(|m| m.role == "assistant")
+                .and_then(|m| m.message_id)
+                .map(MessageId);
+            Some((history_key, answer))
+        })
+    }
+
+    /// Start a named conversation with an optional system prompt and switch to it
+    /// # Errors
+    /// Telegram API failure
+    pub async fn new_conversation(&self, prompt: String) -> ResponseResult<()> {
+        let response = match self.create_conversation(prompt.trim()) {
+            Ok(resp_string) => resp_string,
+            Err(error) => format!("{error}."),
+        };
+
+        self.bot.send_message(self.msg.chat.id, response).await?;
+        Ok(())
+    }
+
+    fn create_conversation(&self, argument: &str) -> anyhow::Result<String> {
+        let (name, prompt) = argument
+            .split_once(char::is_whitespace)
+            .unwrap_or((argument, ""));
+        if name.is_empty() {
+            return Ok("Usage: '/new <name> [system prompt]'".to_string());
+        }
+        let name = named_conversations::parse_name(name)?;
+        let prompt = prompt.trim();
+
+        let config = ConfigManager::new()?;
+        named_conversations::create(
+            &ChatSettingsStore::open()?,
+            &self.msg.chat.id.to_string(),
+            &name,
+            prompt,
+            config.conversation.max_conversations,
+        )?;
+        Ok(if prompt.is_empty() {
+            format!("Started the conversation '{name}'.")
+        } else {
+            format!("Started the conversation '{name}' with prompt '{prompt}'.")
+        })
+    }
+
+    /// Continue another named conversation of this chat
+    /// # Errors
+    /// Telegram API failure
+    pub async fn switch_conversation(&self, prompt: String) -> ResponseResult<()> {
+        let response = if prompt.trim().is_empty() {
+            "Usage: '/switch <name>', see /list for the names".to_string()
+        } else {
+            match named_conversations::parse_name(&prompt).and_then(|name| {
+                let store = ChatSettingsStore::open()?;
+                named_conversations::switch(&store, &self.msg.chat.id.to_string(), &name)?;
+                Ok(name)
+            }) {
+                Ok(name) => format!("Switched to the conversation '{name}'."),
+                Err(error) => format!("{error}."),
+            }
+        };
+
+        self.bot.send_message(self.msg.chat.id, response).await?;
+        Ok(())
+    }
+
+    /// List the named conversations of this chat and which one is active
+    /// # Errors
+    /// Telegram API failure
+    pub async fn list_conversations(&self) -> ResponseResult<()> {
+        let response = match ChatSettingsStore::open()
+            .and_then(|store| store.get(&self.msg.chat.id.to_string()))
+        {
+            Ok(settings) => {
+                let active = settings.active_conversation.as_deref().unwrap_or(MAIN);
+                let lines: Vec<String> = std::iter::once(MAIN)
+                    .chain(settings.conversations.iter().map(String::as_str))
+                    .map(|name| {
+                        if name == active {
+                            format!("▶ {name} (active)")
+                        } else {
+                            format!("• {name}")
+                        }
+                    })
+                    .collect();
+                format!(
+                    "Conversations of this chat:\n{}\n\nUse /new, /switch or /delete to manage them.",
+                    lines.join("\n")
+                )
+            }
+            Err(error) => format!("Error while reading the chat settings: {error}"),
+        };
+
+        self.bot.send_message(self.msg.chat.id, response).await?;
+        Ok(())
+    }
+
+    /// Delete a named conversation and its history
+    /// # Errors
+    /// Telegram API failure
+    pub async fn delete_conversation(&self, prompt: String) -> ResponseResult<()> {
+        let response = if prompt.trim().is_empty() {
+            "Usage: '/delete <name>', see /list for the names".to_string()
+        } else {
+            match named_conversations::parse_name(&prompt).and_then(|name| {
+                let store = ChatSettingsStore::open()?;
+                let index = ThreadIndex::open()?;
+                let chat_id = self.msg.chat.id.to_string();
+                let was_active = named_conversations::delete(&store, &index, &chat_id, &name)?;
+                Ok((name, was_active))
+            }) {
+                Ok((name, true)) => {
+                    format!("Deleted the conversation '{name}', back to the {MAIN} conversation.")
+                }
+                Ok((name, false)) => format!("Deleted the conversation '{name}'."),
+                Err(error) => format!("{error}."),
+            }
+        };
+
+        self.bot.send_message(self.msg.chat.id, response).await?;
+        Ok(())
+    }
+
+    /// Use, list, show, save or delete the system prompts of the persona library
+    /// # Errors
+    /// Telegram API failure
+    pub async fn persona(&self, prompt: String) -> ResponseResult<()> {
+        let response = match self.run_persona(personas::parse_command(&prompt)) {
+            Ok(resp_string) => resp_string,
+            Err(error) => format!("{error}."),
+        };
+
+        for part in split_message(&response, TELEGRAM_MESSAGE_LIMIT) {
+            self.bot.send_message(self.msg.chat.id, part).await?;
+        }
+        Ok(())
+    }
+
+    fn run_persona(&self, command: PersonaCommand) -> anyhow::Result<String> {
+        let config = ConfigManager::new()?;
+        let store = ChatSettingsStore::open()?;
+        let chat_id = self.msg.chat.id.to_string();
+        let settings = store.get(&chat_id)?;
+
+        Ok(match command {
+            PersonaCommand::Usage => "Usage: '/persona <name>', '/persona list', \
+                '/persona show [name]', '/persona save <name> [prompt]' or \
+                '/persona delete <name>'"
+                .to_string(),
+            PersonaCommand::Use(name) => {
+                let Some(prompt) = personas::find(&config.persona, &settings, &name) else {
+                    return Ok(format!(
+                        "There is no persona named '{name}', see /persona list."
+                    ));
+                };
+                // Like /persona show and save, a reply applies to the thread it replies to
+                let history_key = self.command_history();
+                ChatHistory::new(&history_key)?.purge(&history_key, prompt)?;
+                self.drop_branches(&history_key);
+                format!("Started the conversation over as the persona '{name}'.")
+            }
+            PersonaCommand::List => {
+                let lines: Vec<String> = personas::names(&config.persona, &settings)
+                    .into_iter()
+                    .map(|(name, saved)| {
+                        if saved {
+                            format!("• {name} (saved in this chat)")
+                        } else {
+                            format!("• {name}")
+                        }
+                    })
+                    .collect();
+                if lines.is_empty() {
+                    "There are no personas yet, add one with '/persona save <name> <prompt>'."
+                        .to_string()
+                } else {
+                    format!("Personas:\n{}", lines.join("\n"))
+                }
+            }
+            PersonaCommand::Show(Some(name)) => {
+                match personas::find(&config.persona, &settings, &name) {
+                    Some(prompt) => format!("Persona '{name}':\n{prompt}"),
+                    None => format!("There is no persona named '{name}', see /persona list."),
+                }
+            }
+            PersonaCommand::Show(None) => match self.system_prompt()? {
+                Some(prompt) => format!("System prompt of this conversation:\n{prompt}"),
+                None => "This conversation has no system prompt.".to_string(),
+            },
+            PersonaCommand::Save { name, prompt } => {
+                let prompt = if prompt.is_empty() {
+                    let Some(current) = self.system_prompt()? else {
+                        return Ok("This conversation has no system prompt to save.".to_string());
+                    };
+                    current
+                } else {
+                    prompt
+                };
+                personas::save(&store, &chat_id, &name, &prompt, config.persona.max_saved)?;
+                format!("Saved the persona '{name}', use it with /persona {name}.")
+            }
+            PersonaCommand::Delete(name) => {
+                personas::delete(&store, &chat_id, &name)?;
+                format!("Deleted the persona '{name}'.")
+            }
+        })
+    }
+
+    /// The system prompt of the conversation the command applies to
+    fn system_prompt(&self) -> anyhow::Result<Option<String>> {
+        let history = ChatHistory::new(&self.command_history())?;
+        Ok(history
+            .messages
+            .into_iter()
+            .next()
+            .filter(|message| message.role == "system")
+            .map(|message| message.content))
+    }
+
     /// Purge the chat history for a given chat ID
     /// # Errors
     /// Telegram API failure
     pub async fn chat_purge(&self, prompt: String) -> ResponseResult<()> {
-        let open_ai = OpenAiApi::new();
+        let open_ai = self.open_ai();
 
-        let chat_id = format!("{}", self.msg.chat.id);
+        let history_key = self.active_history();
 
-        let response = match open_ai.chat_purge(&chat_id, &prompt) {
-            Ok(resp_string) => resp_string,
+        let response = match open_ai.chat_purge(&history_key, &prompt) {
+            Ok(resp_string) => {
+                self.drop_branches(&history_key);
+                resp_string
+            }
             Err(error) => format!("Error during API call: {error}"),
         };
 
@@ -85,10 +1473,10 @@ impl Response {
     /// # Errors
     /// Telegram API failure
     pub async fn image(&self, prompt: String) -> ResponseResult<()> {
-        let open_ai = OpenAiApi::new();
+        let open_ai = self.open_ai();
         let response = match open_ai.image(prompt.clone()).await {
             Ok(resp_string) => resp_string,
-            Err(error) => format!("Error during API call: {error}"),
+            Err(error) => describe_error("Error during API call", &error),
         };
 
         // If the result is a properly formed URL, send it as an image
@@ -105,6 +1493,54 @@ impl Response {
         Ok(())
     }
 
+    /// Report the usage of this chat and of the sender for today and this month
+    /// # Errors
+    /// Telegram API failure
+    pub async fn usage(&self) -> ResponseResult<()> {
+        let chat_id = self.msg.chat.id.to_string();
+        let user_id = self.msg.from.as_ref().map(|user| user.id.0);
+
+        let response = match usage_report(&chat_id, user_id) {
+            Ok(report) => report,
+            Err(error) => format!("Error while reading usage: {error}"),
+        };
+
+        self.bot.send_message(self.msg.chat.id, response).await?;
+        Ok(())
+    }
+
+    /// Let an admin lift the quotas of a user or this chat for a while
+    /// # Errors
+    /// Telegram API failure
+    pub async fn grant(&self, prompt: String) -> ResponseResult<()> {
+        let response = match self.grant_override(&prompt) {
+            Ok(resp_string) => resp_string,
+            Err(error) => format!("Error while granting override: {error}"),
+        };
+
+        self.bot.send_message(self.msg.chat.id, response).await?;
+        Ok(())
+    }
+
+    fn grant_override(&self, prompt: &str) -> anyhow::Result<String> {
+        let config = ConfigManager::new()?;
+        let user_id = self.msg.from.as_ref().map(|user| user.id.0);
+        let level = permission_of(&config.access, user_id, self.msg.chat.id.0);
+        if level != Some(Permission::Admin) {
+            return Ok("Only admins can grant quota overrides.".to_string());
+        }
+
+        let (target, duration) = parse_grant(prompt, &self.msg.chat.id.to_string())?;
+        let expires_at = Utc::now()
+            .checked_add_signed(duration)
+            .ok_or_else(|| anyhow::anyhow!("The grant would end too far in the future"))?;
+        QuotaOverrides::open()?.grant(target.clone(), expires_at)?;
+        Ok(format!(
+            "Quotas lifted for {target} until {}.",
+            expires_at.format("%Y-%m-%d %H:%M UTC")
+        ))
+    }
+
     /// Lets go gambling!
     /// # Errors
     /// Telegram API failure
@@ -139,3 +1575,85 @@ impl Response {
         Ok(())
     }
 }
+
+/// What the buttons under an answer do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnswerAction {
+    Regenerate,
+    Continue,
+    Forget,
+}
+
+impl AnswerAction {
+    /// Callback data of the button, Telegram allows up to 64 bytes
+    fn data(self) -> &'static str {
+        match self {
+            AnswerAction::Regenerate => "answer:regenerate",
+            AnswerAction::Continue => "answer:continue",
+            AnswerAction::Forget => "answer:forget",
+        }
+    }
+
+    fn parse(data: &str) -> Option<Self> {
+        [
+            AnswerAction::Regenerate,
+            AnswerAction::Continue,
+            AnswerAction::Forget,
+        ]
+        .into_iter()
+        .find(|action| action.data() == data)
+    }
+}
+
+fn answer_keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[
+        InlineKeyboardButton::callback("🔄 Regenerate", AnswerAction::Regenerate.data()),
+        InlineKeyboardButton::callback("➡️ Continue", AnswerAction::Continue.data()),
+        InlineKeyboardButton::callback("🗑 Forget this turn", AnswerAction::Forget.data()),
+    ]])
+}
+
+/// Explain a failed request to the chat, API errors get an actionable message of their own
+fn describe_error(context: &str, error: &anyhow::Error) -> String {
+    if let Some(quota_error) = error.downcast_ref::<QuotaExceeded>() {
+        info!("{context}: {quota_error}");
+        return quota_error.user_message();
+    }
+    match error.downcast_ref::<ApiError>() {
+        Some(api_error) => {
+            warn!("{context}: {api_error}");
+            api_error.user_message()
+        }
+        None => format!("{context}: {error}"),
+    }
+}
+
+fn usage_report(chat_id: &str, user_id: Option<u64>) -> anyhow::Result<String> {
+    let ledger = UsageLedger::open()?;
+    let now = Utc::now();
+
+    let mut report = String::new();
+    for (period, since) in [("Today", day_start(now)), ("This month", month_start(now))] {
+        report.push_str(&format!("{period} (UTC):\n"));
+        let chat = ledger.totals(UsageScope::Chat(chat_id), since)?;
+        report.push_str(&format!("- This chat: {}\n", describe_totals(&chat)));
+        if let Some(user_id) = user_id {
+            let user = ledger.totals(UsageScope::User(user_id), since)?;
+            report.push_str(&format!("- You: {}\n", describe_totals(&user)));
+        }
+    }
+    Ok(report)
+}
+
+fn describe_totals(totals: &UsageTotals) -> String {
+    format!(
+        "{} tokens ({} prompt, {} completion), {} images, {}s of audio transcribed, {} characters read out, ${:.4}",
+        totals.tokens(),
+        totals.prompt_tokens,
+        totals.completion_tokens,
+        totals.images,
+        totals.audio_seconds,
+        totals.speech_chars,
+        totals.cost
+    )
+}
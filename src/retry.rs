@@ -0,0 +1,270 @@
+use super::api_error::ApiError;
+use super::config_manager::RetryConfig;
+
+use anyhow::Result;
+
+use log::warn;
+
+use rand::Rng;
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+use std::time::{Duration, SystemTime};
+
+// Rate limit headers that tell how long until the limits are refilled
+const RATE_LIMIT_RESET_HEADERS: [&str; 2] =
+    ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"];
+
+/// Send a request, retrying rate limits, server errors and network failures as configured.
+/// The response of the last attempt is returned even when it is an error status.
+/// # Errors
+/// `ApiError::Network` when the last attempt failed to get a response
+pub async fn send_with_retry(request: RequestBuilder, policy: &RetryConfig) -> Result<Response> {
+    let mut attempt = 1;
+    loop {
+        // Requests with a streamed body cannot be repeated
+        let Some(this_try) = request
+            .try_clone()
+            .filter(|_| attempt < policy.max_attempts)
+        else {
+            return Ok(request.send().await.map_err(ApiError::from)?);
+        };
+
+        let delay = match this_try.send().await {
+            Ok(response) if !is_retryable(response.status()) => return Ok(response),
+            Ok(response) => {
+                let delay = server_delay(response.headers())
+                    .unwrap_or_else(|| backoff_delay(policy, attempt));
+                warn!(
+                    "Request failed with {} on attempt {attempt}, retrying in {delay:?}",
+                    response.status()
+                );
+                delay
+            }
+            Err(error) if error.is_builder() => return Err(error.into()),
+            Err(error) => {
+                let delay = backoff_delay(policy, attempt);
+                warn!("Request failed with {error} on attempt {attempt}, retrying in {delay:?}");
+                delay
+            }
+        };
+
+        tokio::time::sleep(delay.min(Duration::from_millis(policy.max_delay_ms))).await;
+        attempt += 1;
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
+
+/// Exponential backoff with random jitter on top
+fn backoff_delay(policy: &RetryConfig, attempt: u32) -> Duration {
+    let base = Duration::from_millis(policy.base_delay_ms)
+        .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(Duration::from_millis(policy.max_delay_ms));
+
+    let jitter = if policy.jitter > 0.0 {
+        rand::rng().random_range(0.0..=policy.jitter)
+    } else {
+        0.0
+    };
+    base.mul_f64(1.0 + jitter)
+}
+
+/// How long the server asked us to wait, if it said so
+pub(crate) fn server_delay(headers: &HeaderMap) -> Option<Duration> {
+    let retry_after = headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after);
+
+    retry_after.or_else(|| {
+        RATE_LIMIT_RESET_HEADERS
+            .iter()
+            .filter_map(|name| headers.get(*name)?.to_str().ok())
+            .filter_map(parse_reset_duration)
+            .max()
+    })
+}
+
+/// `Retry-After` holds either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Rate limit resets are durations such as `20ms`, `1.5s` or `6m0s`
+fn parse_reset_duration(value: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+
+    while !rest.is_empty() {
+        let number_end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..number_end].parse().ok()?;
+        rest = &rest[number_end..];
+
+        let unit_end = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit_end] {
+            "ms" => number / 1000.0,
+            "s" | "" => number,
+            "m" => number * 60.0,
+            "h" => number * 3600.0,
+            _ => return None,
+        };
+        rest = &rest[unit_end..];
+
+        total += Duration::try_from_secs_f64(seconds).ok()?;
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn fast_policy(max_attempts: u32) -> RetryConfig {
+        RetryConfig {
+            max_attempts,
+            base_delay_ms: 1,
+            max_delay_ms: 50,
+            jitter: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_retry_after("0.5"), Some(Duration::from_millis(500)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn test_parse_reset_duration() {
+        assert_eq!(
+            parse_reset_duration("20ms"),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(
+            parse_reset_duration("1.5s"),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(parse_reset_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(
+            parse_reset_duration("1h2m3s"),
+            Some(Duration::from_secs(3723))
+        );
+        assert_eq!(parse_reset_duration("later"), None);
+    }
+
+    #[test]
+    fn test_backoff_delay_grows_and_caps() {
+        let policy = RetryConfig {
+            max_attempts: 10,
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+            jitter: 0.0,
+        };
+        assert_eq!(backoff_delay(&policy, 1), Duration::from_millis(100));
+        assert_eq!(backoff_delay(&policy, 3), Duration::from_millis(400));
+        assert_eq!(backoff_delay(&policy, 8), Duration::from_millis(1000));
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let request = reqwest::Client::new()
+            .post(format!("{}/chat", server.uri()))
+            .body("{}");
+        let response = send_with_retry(request, &fast_policy(4)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn test_retry_honors_rate_limit_headers() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(429).insert_header("x-ratelimit-reset-requests", "40ms"),
+            )
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let started = std::time::Instant::now();
+        let request = reqwest::Client::new().get(server.uri());
+        let response = send_with_retry(request, &fast_policy(3)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(started.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let request = reqwest::Client::new().get(server.uri());
+        let response = send_with_retry(request, &fast_policy(3)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_not_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let request = reqwest::Client::new().get(server.uri());
+        let response = send_with_retry(request, &fast_policy(3)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,15 @@
+use anyhow::Result;
+
+use teloxide::net::Download;
+use teloxide::prelude::*;
+use teloxide::types::FileId;
+
+/// Download a file that was sent to the bot
+/// # Errors
+/// Telegram API errors
+pub async fn download(bot: &Bot, file_id: &FileId) -> Result<Vec<u8>> {
+    let file = bot.get_file(file_id.clone()).await?;
+    let mut data = Vec::new();
+    bot.download_file(&file.path, &mut data).await?;
+    Ok(data)
+}
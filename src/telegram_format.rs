@@ -0,0 +1,209 @@
+// Telegram HTML supports b, i, s, code, pre, a and blockquote, everything else is escaped
+
+/// Escape the characters that are markup in Telegram HTML
+#[must_use]
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Convert the Markdown written by chat models into Telegram HTML.
+/// Fenced code becomes `pre` blocks with the language kept, headings become bold lines and list
+/// bullets become `•`. Anything that is not recognised is kept as escaped text.
+#[must_use]
+pub fn markdown_to_html(markdown: &str) -> String {
+    let mut out: Vec<String> = Vec::new();
+    let mut code: Option<(String, Vec<&str>)> = None;
+    let mut quote: Vec<String> = Vec::new();
+
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+
+        if let Some((language, lines)) = &mut code {
+            if trimmed.starts_with("```") {
+                out.push(code_block(language, lines));
+                code = None;
+            } else {
+                lines.push(line);
+            }
+            continue;
+        }
+
+        if let Some(rest) = trimmed.strip_prefix('>') {
+            quote.push(inline(rest.strip_prefix(' ').unwrap_or(rest)));
+            continue;
+        }
+        if !quote.is_empty() {
+            out.push(format!("<blockquote>{}</blockquote>", quote.join("\n")));
+            quote.clear();
+        }
+
+        if let Some(language) = trimmed.strip_prefix("```") {
+            code = Some((language.trim().to_string(), Vec::new()));
+        } else if let Some(heading) = heading(trimmed) {
+            out.push(format!("<b>{}</b>", inline(heading)));
+        } else if let Some(item) = ["- ", "* ", "+ "]
+            .iter()
+            .find_map(|bullet| trimmed.strip_prefix(bullet))
+        {
+            let indent = &line[..line.len() - trimmed.len()];
+            out.push(format!("{indent}• {}", inline(item)));
+        } else {
+            out.push(inline(line));
+        }
+    }
+
+    // Unterminated blocks are closed at the end of the text
+    if let Some((language, lines)) = &code {
+        out.push(code_block(language, lines));
+    }
+    if !quote.is_empty() {
+        out.push(format!("<blockquote>{}</blockquote>", quote.join("\n")));
+    }
+    out.join("\n")
+}
+
+fn code_block(language: &str, lines: &[&str]) -> String {
+    let code = escape_html(&lines.join("\n"));
+    if language.is_empty() {
+        format!("<pre>{code}</pre>")
+    } else {
+        format!(
+            "<pre><code class=\"language-{}\">{code}</code></pre>",
+            escape_html(language).replace('"', "&quot;")
+        )
+    }
+}
+
+/// Text of a `#` to `######` heading line
+fn heading(line: &str) -> Option<&str> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if (1..=6).contains(&level) {
+        line[level..].strip_prefix(' ').map(str::trim)
+    } else {
+        None
+    }
+}
+
+/// Convert the spans of one line: code, links, bold, italic and strikethrough
+fn inline(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        if let Some((html, len)) = span(rest, &out) {
+            out.push_str(&html);
+            rest = &rest[len..];
+        } else {
+            out.push_str(&escape_html(&rest[..c.len_utf8()]));
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/// The span starting at the beginning of `text` as HTML and its length in the Markdown
+fn span(text: &str, before: &str) -> Option<(String, usize)> {
+    if let Some(inner) = text.strip_prefix('`') {
+        let end = inner.find('`').filter(|end| *end > 0)?;
+        return Some((
+            format!("<code>{}</code>", escape_html(&inner[..end])),
+            end + 2,
+        ));
+    }
+
+    if let Some(inner) = text.strip_prefix('[') {
+        let label_end = inner.find("](")?;
+        let url_start = label_end + 2;
+        let url_end = url_start + inner[url_start..].find(')')?;
+        let url = &inner[url_start..url_end];
+        if label_end == 0 || url.is_empty() || url.contains(char::is_whitespace) {
+            return None;
+        }
+        return Some((
+            format!(
+                "<a href=\"{}\">{}</a>",
+                escape_html(url).replace('"', "&quot;"),
+                inline(&inner[..label_end])
+            ),
+            url_end + 2,
+        ));
+    }
+
+    for (delimiter, tag) in [
+        ("**", "b"),
+        ("__", "b"),
+        ("~~", "s"),
+        ("*", "i"),
+        ("_", "i"),
+    ] {
+        let Some(inner) = text.strip_prefix(delimiter) else {
+            continue;
+        };
+        // Underscores inside words such as snake_case are not emphasis
+        let after_word = before.chars().last().is_some_and(char::is_alphanumeric);
+        if delimiter.starts_with('_') && after_word {
+            continue;
+        }
+        let Some(end) = inner.find(delimiter) else {
+            continue;
+        };
+        let content = &inner[..end];
+        let closes_word = inner[end + delimiter.len()..]
+            .chars()
+            .next()
+            .is_some_and(char::is_alphanumeric);
+        if content.is_empty()
+            || content.starts_with(char::is_whitespace)
+            || content.ends_with(char::is_whitespace)
+            || (delimiter.starts_with('_') && closes_word)
+        {
+            continue;
+        }
+        return Some((
+            format!("<{tag}>{}</{tag}>", inline(content)),
+            end + 2 * delimiter.len(),
+        ));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inline_spans() {
+        assert_eq!(
+            markdown_to_html("Use **bold**, *italic*, ~~old~~ and `a < b`"),
+            "Use <b>bold</b>, <i>italic</i>, <s>old</s> and <code>a &lt; b</code>"
+        );
+        assert_eq!(
+            markdown_to_html("See [the *docs*](https://example.com/?a=1&b=2)"),
+            "See <a href=\"https://example.com/?a=1&amp;b=2\">the <i>docs</i></a>"
+        );
+        // Lone markers and identifiers stay as they are
+        assert_eq!(
+            markdown_to_html("2 * 3 = 6, my_var_name and 5 > 3"),
+            "2 * 3 = 6, my_var_name and 5 &gt; 3"
+        );
+    }
+
+    #[test]
+    fn test_blocks() {
+        let markdown = "# Title\n- one\n  * two\n> quoted\n> more\n\n```rust\nfn main() -> i32 { 1 << 2 }\n```\nafter";
+        assert_eq!(
+            markdown_to_html(markdown),
+            "<b>Title</b>\n• one\n  • two\n<blockquote>quoted\nmore</blockquote>\n\n<pre><code class=\"language-rust\">fn main() -&gt; i32 { 1 &lt;&lt; 2 }</code></pre>\nafter"
+        );
+    }
+
+    #[test]
+    fn test_code_is_not_formatted() {
+        assert_eq!(
+            markdown_to_html("```\nlet **x** = `y`;"),
+            "<pre>let **x** = `y`;</pre>"
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use super::chat_history::ChatHistory;
+use super::config_manager::ConfigManager;
+
+use anyhow::Result;
+
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Mutex, PoisonError};
+
+// Oldest bot messages are forgotten past this many per chat, replies to them start over
+const MAX_MESSAGES_PER_CHAT: usize = 2000;
+
+// Updates from concurrent replies must not overwrite each other
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+/// The history a bot message was answered from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ThreadEntry {
+    /// Key of the history in the history store
+    pub history_key: String,
+    /// Telegram message of the history entry, the first one for answers sent in parts
+    pub anchor: i32,
+    /// User who asked the question, only they and admins can change the answer
+    #[serde(default)]
+    pub asker: Option<u64>,
+}
+
+type Index = HashMap<String, BTreeMap<i32, ThreadEntry>>;
+
+/// Maps the messages sent by the bot to the history they belong to, kept in one JSON file
+pub struct ThreadIndex {
+    path: PathBuf,
+}
+
+impl ThreadIndex {
+    #[must_use]
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// The index file named in the config file
+    /// # Errors
+    /// Config file errors
+    pub fn open() -> Result<Self> {
+        let config = ConfigManager::new()?;
+        Ok(Self::new(Path::new(&config.conversation.threads_path)))
+    }
+
+    fn load(&self) -> Result<Index> {
+        if !self.path.is_file() {
+            return Ok(HashMap::new());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(&self.path)?)?)
+    }
+
+    /// The history a bot message belongs to, if it is known
+    /// # Errors
+    /// OS file read or deserialization errors
+    pub fn lookup(&self, chat_id: &str, message_id: i32) -> Result<Option<ThreadEntry>> {
+        Ok(self
+            .load()?
+            .get(chat_id)
+            .and_then(|messages| messages.get(&message_id))
+            .cloned())
+    }
+
+    /// Remember that the bot messages `message_ids` of a chat belong to `entry`
+    /// # Errors
+    /// OS file errors
+    pub fn remember(&self, chat_id: &str, message_ids: &[i32], entry: &ThreadEntry) -> Result<()> {
+        let _guard = INDEX_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut index = self.load()?;
+        let messages = index.entry(chat_id.to_string()).or_default();
+        for message_id in message_ids {
+            messages.insert(*message_id, entry.clone());
+        }
+        while messages.len() > MAX_MESSAGES_PER_CHAT {
+            messages.pop_first();
+        }
+
+        self.store(&index)
+    }
+
+    /// Forget the bot messages of `history_key` and of the branches started from it
+    /// # Errors
+    /// OS file errors
+    pub fn forget_history(&self, chat_id: &str, history_key: &str) -> Result<()> {
+        let _guard = INDEX_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut index = self.load()?;
+        let Some(messages) = index.get_mut(chat_id) else {
+            return Ok(());
+        };
+
+        let prefix = branch_prefix(history_key);
+        messages.retain(|_, entry| {
+            entry.history_key != history_key && !entry.history_key.starts_with(&prefix)
+        });
+        if messages.is_empty() {
+            index.remove(chat_id);
+        }
+        self.store(&index)
+    }
+
+    /// Only call while holding `INDEX_LOCK`
+    fn store(&self, index: &Index) -> Result<()> {
+        let temp_path = self
+            .path
+            .with_extension(format!("json.{}.tmp", process::id()));
+        fs::write(&temp_path, serde_json::to_string(index)?)?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Key of the branch that message `message_id` starts from the history `history_key`.
+/// Branches are named after the history they come from, so they can be found again when it
+/// is purged or deleted.
+#[must_use]
+pub fn branch_key(history_key: &str, message_id: i32) -> String {
+    format!("{}{message_id}", branch_prefix(history_key))
+}
+
+// Branches of branches are named after them as well, they all start with the same prefix.
+// Conversation names cannot have a `.`, so no other history starts with it.
+fn branch_prefix(history_key: &str) -> String {
+    format!("{history_key}.t")
+}
+
+/// Delete the branches started from `history_key` and forget the bot messages of it and of
+/// its branches, so replies to them continue the main history again
+/// # Errors
+/// Index or history store errors
+pub fn drop_branches(index: &ThreadIndex, chat_id: &str, history_key: &str) -> Result<()> {
+    index.forget_history(chat_id, history_key)?;
+    for branch in ChatHistory::list(&branch_prefix(history_key))? {
+        ChatHistory::delete(&branch)?;
+    }
+    Ok(())
+}
+
+/// Key of the history that the message `message_id` continues.
+/// Replies to a bot message continue the history that message was answered from. If that
+/// history went on past the message, a new branch is started from it, so parallel threads in
+/// one chat keep their own context. Any other message continues `main_key`, the history of the
+/// conversation the chat has active.
+/// # Errors
+/// Index or history store errors
+pub fn resolve_thread(
+    index: &ThreadIndex,
+    chat_id: &str,
+    main_key: &str,
+    reply_to: Option<i32>,
+    message_id: i32,
+) -> Result<String> {
+    let Some(entry) = reply_to
+        .map(|reply_to| index.lookup(chat_id, reply_to))
+        .transpose()?
+        .flatten()
+    else {
+        return Ok(main_key.to_string());
+    };
+
+    let history = ChatHistory::new(&entry.history_key)?;
+    match history.position_of(entry.anchor) {
+        Some(position) if position + 1 < history.messages.len() => {
+            let branch = branch_key(&entry.history_key, message_id);
+            if ChatHistory::fork(&entry.history_key, &branch, entry.anchor)? {
+                Ok(branch)
+            } else {
+                Ok(entry.history_key)
+            }
+        }
+        _ => Ok(entry.history_key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_history::{Exchange, Role};
+
+    #[test]
+    fn test_reply_threads() {
+        let index_path = Path::new("test-chat-threads.json");
+        let index = ThreadIndex::new(index_path);
+        let chat_id = "test-threads";
+        ChatHistory::new(chat_id)
+            .unwrap()
+            .purge(chat_id, "base")
+            .unwrap();
+
+        // First exchange in the main history, answered in bot message 11
+        let history = ChatHistory::new(chat_id)
+            .unwrap()
+            .add_entry(chat_id, &Role::User, "first")
+            .unwrap()
+            .add_entry(chat_id, &Role::Assistant, "answer one")
+            .unwrap();
+        let exchange = Exchange {
+            question: Some(history.messages.len() - 2),
+            answer: history.messages.len() - 1,
+            answer_text: "answer one".to_string(),
+        };
+        drop(history);
+        ChatHistory::tag_exchange(chat_id, &exchange, 10, 11).unwrap();
+        let entry = ThreadEntry {
+            history_key: chat_id.to_string(),
+            anchor: 11,
+            asker: Some(1),
+        };
+        index.remember(chat_id, &[11], &entry).unwrap();
+
+        // Plain messages and replies to the newest answer stay in the main history
+        assert_eq!(
+            resolve_thread(&index, chat_id, chat_id, None, 12).unwrap(),
+            chat_id
+        );
+        assert_eq!(
+            resolve_thread(&index, chat_id, chat_id, Some(11), 12).unwrap(),
+            chat_id
+        );
+
+        ChatHistory::new(chat_id)
+            .unwrap()
+            .add_entry(chat_id, &Role::User, "unrelated")
+            .unwrap()
+            .add_entry(chat_id, &Role::Assistant, "answer two")
+            .unwrap();
+
+        // Replying to the first answer now branches off from it
+        let branch = resolve_thread(&index, chat_id, chat_id, Some(11), 14).unwrap();
+        assert_eq!(branch, format!("{chat_id}.t14"));
+        let contents: Vec<String> = ChatHistory::new(&branch)
+            .unwrap()
+            .messages
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(contents, vec!["base", "first", "answer one"]);
+
+        // Purging the main history takes its branches along
+        drop_branches(&index, chat_id, chat_id).unwrap();
+        assert!(ChatHistory::find(&branch).unwrap().is_none());
+        assert_eq!(index.lookup(chat_id, 11).unwrap(), None);
+
+        fs::remove_file(index_path).unwrap();
+        ChatHistory::delete(chat_id).unwrap();
+    }
+}
@@ -0,0 +1,462 @@
+use super::chat_history::ToolCall;
+use super::config_manager::ToolConfig;
+
+use anyhow::{anyhow, bail, Result};
+
+use async_trait::async_trait;
+
+use chrono::{FixedOffset, Utc};
+
+use log::{debug, warn};
+
+use rand::Rng;
+
+use serde_json::{json, Value};
+
+// Bounds of a single dice roll, larger requests are refused
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+const MAX_MODIFIER: i64 = 1_000_000;
+
+// Deeper expressions are refused instead of running the parser out of stack
+const MAX_DEPTH: usize = 64;
+
+/// A tool the chat model can call, described to the model by its name and a JSON schema
+#[async_trait]
+pub trait Tool: Send + Sync {
+    /// Name the model calls the tool by
+    fn name(&self) -> &'static str;
+
+    /// What the tool does, for the model to decide when to call it
+    fn description(&self) -> &'static str;
+
+    /// JSON schema of the arguments object
+    fn parameters(&self) -> Value;
+
+    /// Run the tool, the returned text is sent back to the model
+    /// # Errors
+    /// Invalid arguments or failure of the tool
+    async fn execute(&self, arguments: Value) -> Result<String>;
+}
+
+/// A tool as it is offered to the model in a chat request
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
+/// The tools the chat model may call
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    /// The built-in tools turned on in the config file, none if tools are off
+    #[must_use]
+    pub fn from_config(config: &ToolConfig) -> Self {
+        let mut registry = Self::default();
+        if !config.enabled {
+            return registry;
+        }
+        for name in &config.tools {
+            match builtin(name) {
+                Some(tool) => registry.register(tool),
+                None => warn!("Unknown tool {name} in the config file"),
+            }
+        }
+        registry
+    }
+
+    /// Offer `tool` to the model, replacing a tool of the same name
+    pub fn register(&mut self, tool: Box<dyn Tool>) {
+        self.tools.retain(|known| known.name() != tool.name());
+        self.tools.push(tool);
+    }
+
+    /// The tools as they are sent with a chat request
+    #[must_use]
+    pub fn specs(&self) -> Vec<ToolSpec> {
+        self.tools
+            .iter()
+            .map(|tool| ToolSpec {
+                name: tool.name().to_string(),
+                description: tool.description().to_string(),
+                parameters: tool.parameters(),
+            })
+            .collect()
+    }
+
+    /// Run a call of the model. Failures are returned as text so the model can react to them.
+    pub async fn execute(&self, call: &ToolCall) -> String {
+        let Some(tool) = self.tools.iter().find(|tool| tool.name() == call.name) else {
+            return format!("Error: there is no tool named {}", call.name);
+        };
+        // Models send an empty string for tools without arguments
+        let arguments = if call.arguments.trim().is_empty() {
+            Ok(json!({}))
+        } else {
+            serde_json::from_str(&call.arguments)
+        };
+        let result = match arguments {
+            Ok(arguments) => tool.execute(arguments).await,
+            Err(error) => Err(anyhow!("the arguments are not valid JSON: {error}")),
+        };
+
+        debug!(
+            "Tool {} called with {}: {result:?}",
+            call.name, call.arguments
+        );
+        result.unwrap_or_else(|error| format!("Error: {error}"))
+    }
+}
+
+fn builtin(name: &str) -> Option<Box<dyn Tool>> {
+    match name {
+        "current_time" => Some(Box::new(CurrentTime)),
+        "calculator" => Some(Box::new(Calculator)),
+        "roll_dice" => Some(Box::new(RollDice)),
+        _ => None,
+    }
+}
+
+/// The current date and time
+pub struct CurrentTime;
+
+#[async_trait]
+impl Tool for CurrentTime {
+    fn name(&self) -> &'static str {
+        "current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "Get the current date, time and weekday."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "utc_offset_hours": {
+                    "type": "number",
+                    "description": "Offset of the time zone from UTC in hours, 0 if not given"
+                }
+            }
+        })
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<String> {
+        let hours = arguments["utc_offset_hours"].as_f64().unwrap_or(0.0);
+        #[allow(clippy::cast_possible_truncation)]
+        let offset = FixedOffset::east_opt((hours * 3600.0).round() as i32)
+            .ok_or_else(|| anyhow!("{hours} hours is not a valid UTC offset"))?;
+        Ok(Utc::now()
+            .with_timezone(&offset)
+            .format("%A %Y-%m-%d %H:%M:%S UTC%:z")
+            .to_string())
+    }
+}
+
+/// Arithmetic on numbers
+pub struct Calculator;
+
+#[async_trait]
+impl Tool for Calculator {
+    fn name(&self) -> &'static str {
+        "calculator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Evaluate an arithmetic expression with + - * / % ^, parentheses, sqrt(), pi and e."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": {
+                    "type": "string",
+                    "description": "The expression, for example (2 + 3) * sqrt(16)"
+                }
+            },
+            "required": ["expression"]
+        })
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<String> {
+        let expression = arguments["expression"]
+            .as_str()
+            .ok_or_else(|| anyhow!("expression is missing"))?;
+        Ok(evaluate(expression)?.to_string())
+    }
+}
+
+/// Random dice rolls
+pub struct RollDice;
+
+#[async_trait]
+impl Tool for RollDice {
+    fn name(&self) -> &'static str {
+        "roll_dice"
+    }
+
+    fn description(&self) -> &'static str {
+        "Roll dice given in dice notation, like 1d6 or 3d8+2, and get every roll and the total."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "dice": {
+                    "type": "string",
+                    "description": "Dice notation, 1d6 if not given"
+                }
+            }
+        })
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<String> {
+        let (count, sides, modifier) = parse_dice(arguments["dice"].as_str().unwrap_or("1d6"))?;
+        let mut rng = rand::rng();
+        let rolls: Vec<u32> = (0..count).map(|_| rng.random_range(1..=sides)).collect();
+        let total = i64::from(rolls.iter().sum::<u32>()) + modifier;
+        Ok(format!("Rolls: {rolls:?}, total: {total}"))
+    }
+}
+
+/// Number of dice, sides per die and the amount added to the total
+fn parse_dice(notation: &str) -> Result<(u32, u32, i64)> {
+    let notation = notation.trim().to_lowercase();
+    let (dice, modifier) = match notation.find(['+', '-']) {
+        Some(at) => (&notation[..at], notation[at..].replace(' ', "").parse()?),
+        None => (notation.as_str(), 0),
+    };
+    let (count, sides) = dice
+        .split_once('d')
+        .ok_or_else(|| anyhow!("{notation} is not dice notation like 2d6"))?;
+    let count = if count.trim().is_empty() {
+        1
+    } else {
+        count.trim().parse()?
+    };
+    let sides: u32 = sides.trim().parse()?;
+
+    if !(1..=MAX_DICE).contains(&count) || !(2..=MAX_SIDES).contains(&sides) {
+        bail!("Roll 1 to {MAX_DICE} dice with 2 to {MAX_SIDES} sides");
+    }
+    if !(-MAX_MODIFIER..=MAX_MODIFIER).contains(&modifier) {
+        bail!("Add at most {MAX_MODIFIER} to the roll");
+    }
+    Ok((count, sides, modifier))
+}
+
+/// Value of an arithmetic expression
+/// # Errors
+/// Syntax errors and results that are not a number
+pub fn evaluate(expression: &str) -> Result<f64> {
+    let mut parser = Parser {
+        chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+        at: 0,
+        depth: 0,
+    };
+    let value = parser.sum()?;
+    if parser.at < parser.chars.len() {
+        bail!("Unexpected '{}' in the expression", parser.chars[parser.at]);
+    }
+    if !value.is_finite() {
+        bail!("The result is not a finite number");
+    }
+    Ok(value)
+}
+
+// Recursive descent over sum > product > unary > power > atom
+struct Parser {
+    chars: Vec<char>,
+    at: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.at).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    // Every recursion of the parser goes through here
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<f64>) -> Result<f64> {
+        if self.depth >= MAX_DEPTH {
+            bail!("The expression is nested too deeply");
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn sum(&mut self) -> Result<f64> {
+        let mut value = self.product()?;
+        loop {
+            if self.eat('+') {
+                value += self.product()?;
+            } else if self.eat('-') {
+                value -= self.product()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<f64> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value *= self.unary()?;
+            } else if self.eat('/') {
+                value /= self.unary()?;
+            } else if self.eat('%') {
+                value %= self.unary()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<f64> {
+        if self.eat('-') {
+            return Ok(-self.nested(Self::unary)?);
+        }
+        if self.eat('+') {
+            return self.nested(Self::unary);
+        }
+        self.power()
+    }
+
+    fn power(&mut self) -> Result<f64> {
+        let base = self.atom()?;
+        // Right associative, 2^3^2 is 2^9
+        if self.eat('^') {
+            return Ok(base.powf(self.nested(Self::unary)?));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64> {
+        if self.eat('(') {
+            let value = self.nested(Self::sum)?;
+            if !self.eat(')') {
+                bail!("A ')' is missing in the expression");
+            }
+            return Ok(value);
+        }
+
+        let start = self.at;
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.at += 1;
+                }
+                let number: String = self.chars[start..self.at].iter().collect();
+                Ok(number.parse()?)
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                    self.at += 1;
+                }
+                let name: String = self.chars[start..self.at].iter().collect();
+                match name.as_str() {
+                    "pi" => Ok(std::f64::consts::PI),
+                    "e" => Ok(std::f64::consts::E),
+                    "sqrt" => Ok(self.nested(Self::atom)?.sqrt()),
+                    _ => bail!("Unknown name '{name}' in the expression"),
+                }
+            }
+            Some(c) => bail!("Unexpected '{c}' in the expression"),
+            None => bail!("The expression ends too early"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: "call_1".to_string(),
+            name: name.to_string(),
+            arguments: arguments.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(evaluate("1 + 2 * 3").unwrap(), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3").unwrap(), 9.0);
+        assert_eq!(evaluate("2^3^2").unwrap(), 512.0);
+        assert_eq!(evaluate("-2^2").unwrap(), -4.0);
+        assert_eq!(evaluate("2^-1").unwrap(), 0.5);
+        assert_eq!(evaluate("sqrt(16) / 8 % 1").unwrap(), 0.5);
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("(1 + 2").is_err());
+        assert!(evaluate("1 + x").is_err());
+        assert_eq!(
+            evaluate(&format!("{}1{}", "(".repeat(60), ")".repeat(60))).unwrap(),
+            1.0
+        );
+        assert!(evaluate(&format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000))).is_err());
+        assert!(evaluate(&format!("{}1", "-".repeat(100_000))).is_err());
+        assert!(evaluate(&format!("{}4)", "sqrt(".repeat(100_000))).is_err());
+    }
+
+    #[test]
+    fn test_parse_dice() {
+        assert_eq!(parse_dice("d20").unwrap(), (1, 20, 0));
+        assert_eq!(parse_dice("3d8 + 2").unwrap(), (3, 8, 2));
+        assert_eq!(parse_dice("2D6-1").unwrap(), (2, 6, -1));
+        assert!(parse_dice("1000d6").is_err());
+        assert!(parse_dice("six").is_err());
+        assert!(parse_dice("1d6+9223372036854775807").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_registry_execute() {
+        let registry = ToolRegistry::from_config(&ToolConfig::default());
+        let names: Vec<String> = registry.specs().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["current_time", "calculator", "roll_dice"]);
+
+        assert_eq!(
+            registry
+                .execute(&call("calculator", r#"{"expression":"6*7"}"#))
+                .await,
+            "42"
+        );
+        assert!(registry
+            .execute(&call("roll_dice", ""))
+            .await
+            .starts_with("Rolls: ["));
+        assert!(registry
+            .execute(&call("calculator", "{"))
+            .await
+            .starts_with("Error: "));
+        assert_eq!(
+            registry.execute(&call("weather", "{}")).await,
+            "Error: there is no tool named weather"
+        );
+
+        let disabled = ToolConfig {
+            enabled: false,
+            ..ToolConfig::default()
+        };
+        assert!(ToolRegistry::from_config(&disabled).specs().is_empty());
+    }
+}
@@ -0,0 +1,496 @@
+use super::config_manager::{ConfigManager, ModelPrice};
+
+use anyhow::Result;
+
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+// Appends from concurrent requests must not interleave
+static LEDGER_LOCK: Mutex<()> = Mutex::new(());
+
+/// Who a request is made for, used to account usage to a chat and a Telegram user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub chat_id: String,
+    pub user_id: Option<u64>,
+}
+
+/// Tokens reported by the API for one request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// One billed request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UsageRecord {
+    /// Unix time in seconds
+    pub timestamp: i64,
+    pub chat_id: String,
+    pub user_id: Option<u64>,
+    pub model: String,
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub images: u64,
+    /// Seconds of audio transcribed
+    #[serde(default)]
+    pub audio_seconds: u64,
+    /// Characters read out as speech
+    #[serde(default)]
+    pub speech_chars: u64,
+    /// Cost in the currency of the price table when the request was made
+    #[serde(default)]
+    pub cost: f64,
+}
+
+impl UsageRecord {
+    /// Record of a chat completion, priced with the current price table
+    #[must_use]
+    pub fn chat(
+        caller: &Caller,
+        model: &str,
+        usage: TokenUsage,
+        prices: &HashMap<String, ModelPrice>,
+    ) -> Self {
+        let price = find_price(prices, model);
+        #[allow(clippy::cast_precision_loss)]
+        let cost = (usage.prompt_tokens as f64 * price.prompt_per_million
+            + usage.completion_tokens as f64 * price.completion_per_million)
+            / 1_000_000.0;
+        Self {
+            timestamp: Utc::now().timestamp(),
+            chat_id: caller.chat_id.clone(),
+            user_id: caller.user_id,
+            model: model.to_string(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            images: 0,
+            audio_seconds: 0,
+            speech_chars: 0,
+            cost,
+        }
+    }
+
+    /// Record of generated images, priced with the current price table
+    #[must_use]
+    pub fn images(
+        caller: &Caller,
+        model: &str,
+        images: u64,
+        prices: &HashMap<String, ModelPrice>,
+    ) -> Self {
+        #[allow(clippy::cast_precision_loss)]
+        let cost = images as f64 * find_price(prices, model).per_image;
+        Self {
+            timestamp: Utc::now().timestamp(),
+            chat_id: caller.chat_id.clone(),
+            user_id: caller.user_id,
+            model: model.to_string(),
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            images,
+            audio_seconds: 0,
+            speech_chars: 0,
+            cost,
+        }
+    }
+
+    /// Record of a transcription of `seconds` of audio, priced with the current price table
+    #[must_use]
+    pub fn transcription(
+        caller: &Caller,
+        model: &str,
+        seconds: u64,
+        prices: &HashMap<String, ModelPrice>,
+    ) -> Self {
+        #[allow(clippy::cast_precision_loss)]
+        let cost = seconds as f64 / 60.0 * find_price(prices, model).per_audio_minute;
+        Self {
+            timestamp: Utc::now().timestamp(),
+            chat_id: caller.chat_id.clone(),
+            user_id: caller.user_id,
+            model: model.to_string(),
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            images: 0,
+            audio_seconds: seconds,
+            speech_chars: 0,
+            cost,
+        }
+    }
+
+    /// Record of `chars` characters read out as speech, priced with the current price table
+    #[must_use]
+    pub fn speech(
+        caller: &Caller,
+        model: &str,
+        chars: u64,
+        prices: &HashMap<String, ModelPrice>,
+    ) -> Self {
+        #[allow(clippy::cast_precision_loss)]
+        let cost = chars as f64 * find_price(prices, model).per_million_chars / 1_000_000.0;
+        Self {
+            timestamp: Utc::now().timestamp(),
+            chat_id: caller.chat_id.clone(),
+            user_id: caller.user_id,
+            model: model.to_string(),
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            images: 0,
+            audio_seconds: 0,
+            speech_chars: chars,
+            cost,
+        }
+    }
+}
+
+/// The price of `model`, by exact name or else the longest matching prefix, such as `gpt-4o`
+/// for `gpt-4o-2024-08-06`. Unknown models are free.
+#[must_use]
+pub fn find_price(prices: &HashMap<String, ModelPrice>, model: &str) -> ModelPrice {
+    prices
+        .iter()
+        .filter(|(name, _)| model.starts_with(name.as_str()))
+        .max_by_key(|(name, _)| name.len())
+        .map(|(_, price)| price.clone())
+        .unwrap_or_default()
+}
+
+/// Summed usage over a set of records
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub images: u64,
+    pub audio_seconds: u64,
+    pub speech_chars: u64,
+    pub cost: f64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, record: &UsageRecord) {
+        self.requests += 1;
+        self.prompt_tokens += record.prompt_tokens;
+        self.completion_tokens += record.completion_tokens;
+        self.images += record.images;
+        self.audio_seconds += record.audio_seconds;
+        self.speech_chars += record.speech_chars;
+        self.cost += record.cost;
+    }
+
+    #[must_use]
+    pub fn tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// Which records to sum up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsageScope<'a> {
+    Chat(&'a str),
+    User(u64),
+    Global,
+}
+
+impl UsageScope<'_> {
+    fn matches(&self, record: &UsageRecord) -> bool {
+        match self {
+            UsageScope::Chat(chat_id) => record.chat_id == *chat_id,
+            UsageScope::User(user_id) => record.user_id == Some(*user_id),
+            UsageScope::Global => true,
+        }
+    }
+}
+
+/// Start of the current UTC day
+#[must_use]
+pub fn day_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(now.year(), now.month(), now.day(), 0, 0, 0)
+        .single()
+        .unwrap_or(now)
+}
+
+/// Start of the current UTC month
+#[must_use]
+pub fn month_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+        .single()
+        .unwrap_or(now)
+}
+
+/// Append-only log of billed requests, one JSON record per line. The records of each
+/// month go to a file of their own next to `path`, so budgets only read the months they
+/// cover: `usage.jsonl` is split into `usage-2024-01.jsonl`, `usage-2024-02.jsonl`...
+pub struct UsageLedger {
+    path: PathBuf,
+}
+
+impl UsageLedger {
+    #[must_use]
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// The ledger file named in the config file
+    /// # Errors
+    /// Config file errors
+    pub fn open() -> Result<Self> {
+        let config = ConfigManager::new()?;
+        Ok(Self::new(Path::new(&config.usage.path)))
+    }
+
+    /// # Errors
+    /// OS file write errors
+    pub fn record(&self, record: &UsageRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        let _guard = LEDGER_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.month_path(record.timestamp))?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Sum the records of `scope` made at or after `since`
+    /// # Errors
+    /// OS file read errors
+    pub fn totals(&self, scope: UsageScope, since: DateTime<Utc>) -> Result<UsageTotals> {
+        let mut totals = UsageTotals::default();
+        let files = self.files_since(since)?;
+
+        let since = since.timestamp();
+        for path in files {
+            let reader = BufReader::new(File::open(path)?);
+            for line in reader.lines() {
+                // A torn or hand edited line should not hide the rest of the ledger
+                let Ok(record) = serde_json::from_str::<UsageRecord>(&line?) else {
+                    continue;
+                };
+                if record.timestamp >= since && scope.matches(&record) {
+                    totals.add(&record);
+                }
+            }
+        }
+        Ok(totals)
+    }
+
+    /// The files that can hold records made at or after `since`
+    /// # Errors
+    /// OS directory read errors
+    pub fn files_since(&self, since: DateTime<Utc>) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+
+        // Records from before the ledger was split by month, the file is no longer
+        // written so it is skipped once it is older than `since`
+        if let Ok(metadata) = self.path.metadata() {
+            if metadata.is_file() && DateTime::<Utc>::from(metadata.modified()?) >= since {
+                files.push(self.path.clone());
+            }
+        }
+
+        let (prefix, suffix) = self.month_affixes();
+        let first = since.format("%Y-%m").to_string();
+        let dir = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        if !dir.is_dir() {
+            return Ok(files);
+        }
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let month = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|name| name.strip_suffix(&suffix));
+            if month.is_some_and(|month| month.len() == first.len() && *month >= *first) {
+                files.push(entry.path());
+            }
+        }
+        files.sort_unstable();
+        Ok(files)
+    }
+
+    /// The file of the records made in the month of `timestamp`
+    fn month_path(&self, timestamp: i64) -> PathBuf {
+        let (prefix, suffix) = self.month_affixes();
+        let month = DateTime::<Utc>::from_timestamp(timestamp, 0)
+            .unwrap_or_default()
+            .format("%Y-%m");
+        self.path.with_file_name(format!("{prefix}{month}{suffix}"))
+    }
+
+    // What goes before and after the month in the name of a month file
+    fn month_affixes(&self) -> (String, String) {
+        let stem = self
+            .path
+            .file_stem()
+            .map_or_else(|| "usage".into(), |stem| stem.to_string_lossy());
+        let suffix = self
+            .path
+            .extension()
+            .map(|extension| format!(".{}", extension.to_string_lossy()))
+            .unwrap_or_default();
+        (format!("{stem}-"), suffix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prices() -> HashMap<String, ModelPrice> {
+        HashMap::from([
+            (
+                "gpt-4o".to_string(),
+                ModelPrice {
+                    prompt_per_million: 2.5,
+                    completion_per_million: 10.0,
+                    per_image: 0.0,
+                    ..ModelPrice::default()
+                },
+            ),
+            (
+                "gpt-4o-mini".to_string(),
+                ModelPrice {
+                    prompt_per_million: 0.15,
+                    completion_per_million: 0.6,
+                    per_image: 0.0,
+                    ..ModelPrice::default()
+                },
+            ),
+        ])
+    }
+
+    fn caller(chat_id: &str, user_id: u64) -> Caller {
+        Caller {
+            chat_id: chat_id.to_string(),
+            user_id: Some(user_id),
+        }
+    }
+
+    #[test]
+    fn test_find_price_longest_prefix() {
+        let prices = prices();
+        assert!((find_price(&prices, "gpt-4o-2024-08-06").prompt_per_million - 2.5).abs() < 1e-9);
+        assert!(
+            (find_price(&prices, "gpt-4o-mini-2024-07-18").prompt_per_million - 0.15).abs() < 1e-9
+        );
+        assert!(find_price(&prices, "llama3").prompt_per_million.abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ledger_totals() {
+        let path = Path::new("test-usage-ledger.jsonl");
+        let ledger = UsageLedger::new(path);
+        let usage = TokenUsage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 100_000,
+        };
+
+        ledger
+            .record(&UsageRecord::chat(
+                &caller("1", 10),
+                "gpt-4o",
+                usage,
+                &prices(),
+            ))
+            .unwrap();
+        ledger
+            .record(&UsageRecord::chat(
+                &caller("1", 20),
+                "gpt-4o",
+                usage,
+                &prices(),
+            ))
+            .unwrap();
+        let mut old = UsageRecord::chat(&caller("2", 10), "gpt-4o", usage, &prices());
+        old.timestamp -= 40 * 24 * 3600;
+        ledger.record(&old).unwrap();
+
+        let now = Utc::now();
+        let chat = ledger
+            .totals(UsageScope::Chat("1"), day_start(now))
+            .unwrap();
+        assert_eq!(chat.requests, 2);
+        assert_eq!(chat.tokens(), 2_200_000);
+        assert!((chat.cost - 7.0).abs() < 1e-9);
+
+        let user = ledger
+            .totals(UsageScope::User(10), month_start(now))
+            .unwrap();
+        assert_eq!(user.requests, 1);
+
+        let all_time = ledger
+            .totals(UsageScope::Global, DateTime::<Utc>::UNIX_EPOCH)
+            .unwrap();
+        assert_eq!(all_time.requests, 3);
+
+        // Audio is billed by the minute and speech by the character
+        let mut prices = prices();
+        prices.insert(
+            "whisper-1".to_string(),
+            ModelPrice {
+                per_audio_minute: 0.006,
+                ..ModelPrice::default()
+            },
+        );
+        prices.insert(
+            "tts-1".to_string(),
+            ModelPrice {
+                per_million_chars: 15.0,
+                ..ModelPrice::default()
+            },
+        );
+        ledger
+            .record(&UsageRecord::transcription(
+                &caller("3", 10),
+                "whisper-1",
+                90,
+                &prices,
+            ))
+            .unwrap();
+        ledger
+            .record(&UsageRecord::speech(
+                &caller("3", 10),
+                "tts-1",
+                2_000,
+                &prices,
+            ))
+            .unwrap();
+        let audio = ledger
+            .totals(UsageScope::Chat("3"), day_start(now))
+            .unwrap();
+        assert_eq!(audio.requests, 2);
+        assert_eq!(audio.audio_seconds, 90);
+        assert_eq!(audio.speech_chars, 2_000);
+        assert!((audio.cost - 0.039).abs() < 1e-9);
+
+        // The record of 40 days ago is in an older month file that this month skips
+        assert_eq!(ledger.files_since(month_start(now)).unwrap().len(), 1);
+        let files = ledger.files_since(DateTime::<Utc>::UNIX_EPOCH).unwrap();
+        assert_eq!(files.len(), 2);
+        for file in files {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
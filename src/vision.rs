@@ -0,0 +1,165 @@
+use super::chat_history::{ImageRef, MessageChat};
+use super::config_manager::VisionConfig;
+use super::telegram_file::download;
+
+use anyhow::Result;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use log::warn;
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use teloxide::prelude::*;
+use teloxide::types::PhotoSize;
+
+// Left in the text of older messages whose pictures are no longer sent
+const IMAGE_NOTE: &str = "[An image was attached here]";
+
+/// Download the largest size of a Telegram photo into the image directory, and delete the
+/// photos that are past their retention
+/// # Errors
+/// Telegram API or OS file errors
+pub async fn download_photo(
+    bot: &Bot,
+    sizes: &[PhotoSize],
+    config: &VisionConfig,
+) -> Result<Option<ImageRef>> {
+    let Some(photo) = sizes.iter().max_by_key(|size| size.width * size.height) else {
+        return Ok(None);
+    };
+
+    let data = download(bot, &photo.file.id).await?;
+
+    let dir = Path::new(&config.image_dir);
+    fs::create_dir_all(dir)?;
+    // Telegram sends every photo as a JPEG
+    let path = dir.join(format!("{}.jpg", photo.file.unique_id));
+    fs::write(&path, data)?;
+
+    if let Err(error) = prune_images(dir, config.image_retention_days) {
+        warn!("Failed to delete old photos: {error}");
+    }
+
+    Ok(Some(ImageRef {
+        path: path.to_string_lossy().into_owned(),
+        mime_type: "image/jpeg".to_string(),
+    }))
+}
+
+/// Delete the files in `dir` last written more than `days` days ago, 0 keeps them all.
+/// Returns how many were deleted.
+/// # Errors
+/// OS file errors
+pub fn prune_images(dir: &Path, days: u64) -> Result<usize> {
+    let retention = Duration::from_secs(days.saturating_mul(24 * 3600));
+    let Some(cutoff) = SystemTime::now()
+        .checked_sub(retention)
+        .filter(|_| days > 0)
+    else {
+        return Ok(0);
+    };
+
+    let mut deleted = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && metadata.modified()? < cutoff {
+            fs::remove_file(entry.path())?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// The contents of an image file as base64, `None` with a warning if it cannot be read
+#[must_use]
+pub fn encode_image(image: &ImageRef) -> Option<String> {
+    match fs::read(&image.path) {
+        Ok(data) => Some(STANDARD.encode(data)),
+        Err(error) => {
+            warn!("Leaving out image {}: {error}", image.path);
+            None
+        }
+    }
+}
+
+/// Keep the pictures of the `keep` newest messages that have any, the older ones only get a
+/// note in their text. Sending every picture of a long chat again would be slow and expensive.
+pub fn keep_recent_images(messages: &mut [MessageChat], keep: usize) {
+    for message in messages
+        .iter_mut()
+        .rev()
+        .filter(|message| !message.images.is_empty())
+        .skip(keep)
+    {
+        message.images.clear();
+        message.content = format!("{IMAGE_NOTE}\n{}", message.content);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: &str, images: usize) -> MessageChat {
+        MessageChat {
+            images: (0..images)
+                .map(|i| ImageRef {
+                    path: format!("{content}-{i}.jpg"),
+                    mime_type: "image/jpeg".to_string(),
+                })
+                .collect(),
+            ..MessageChat::new("user", content)
+        }
+    }
+
+    #[test]
+    fn test_keep_recent_images() {
+        let mut messages = vec![
+            message("old", 1),
+            message("text", 0),
+            message("middle", 2),
+            message("new", 1),
+        ];
+        keep_recent_images(&mut messages, 2);
+        assert!(messages[0].images.is_empty());
+        assert_eq!(messages[0].content, format!("{IMAGE_NOTE}\nold"));
+        assert_eq!(messages[1].content, "text");
+        assert_eq!(messages[2].images.len(), 2);
+        assert_eq!(messages[3].images.len(), 1);
+    }
+
+    #[test]
+    fn test_prune_images() {
+        let dir = Path::new("test-prune-images");
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("new.jpg"), b"jpeg").unwrap();
+        let old = fs::File::create(dir.join("old.jpg")).unwrap();
+        old.set_modified(SystemTime::now() - Duration::from_secs(3 * 24 * 3600))
+            .unwrap();
+        drop(old);
+
+        assert_eq!(prune_images(dir, 0).unwrap(), 0);
+        assert_eq!(prune_images(dir, 2).unwrap(), 1);
+        assert!(dir.join("new.jpg").is_file());
+        assert!(!dir.join("old.jpg").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_encode_image() {
+        let path = "test-encode-image.jpg";
+        fs::write(path, b"jpeg").unwrap();
+        let image = ImageRef {
+            path: path.to_string(),
+            mime_type: "image/jpeg".to_string(),
+        };
+        assert_eq!(encode_image(&image).as_deref(), Some("anBlZw=="));
+        fs::remove_file(path).unwrap();
+        assert_eq!(encode_image(&image), None);
+    }
+}